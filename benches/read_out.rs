@@ -0,0 +1,83 @@
+//! Throughput of reading the shell output up to the prompt. Compares the former byte by byte
+//! reading, which searched the prompt in the whole output after each byte, with the ShellReader.
+//! Run with 'cargo bench --bench read_out'.
+
+#[path = "../src/runtime/shell_reader.rs"]
+#[allow(dead_code)]
+mod shell_reader;
+
+use regex::Regex;
+use shell_reader::ShellReader;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// Line of the generated output, similar to the journalctl output
+const LINE: &str = "Oct 18 12:00:01 host systemd[1]: Started Session 1234 of user root.\r\n";
+
+/// Prompt which terminates the generated output
+const PROMPT: &str = "qwerty";
+
+/// Generates the output of the specified size followed by the prompt
+fn output(size: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(size + PROMPT.len());
+    while out.len() < size {
+        out.extend_from_slice(LINE.as_bytes());
+    }
+    out.truncate(size);
+    out.extend_from_slice(PROMPT.as_bytes());
+    out
+}
+
+/// Former implementation of the reading
+fn read_bytewise<R: Read>(reader: &mut R, prompt: &Regex) -> String {
+    let mut out = String::new();
+    loop {
+        let mut buf = vec![0; 1];
+        reader.read_exact(&mut buf).unwrap();
+        let utf8 = std::str::from_utf8(&buf);
+        if utf8.is_ok() {
+            out.push_str(utf8.unwrap());
+            let pf = prompt.find(&out);
+            if pf.is_some() {
+                let len = pf.unwrap().end() - pf.unwrap().start();
+                return String::from(&out[..out.len() - len]);
+            }
+        }
+    }
+}
+
+/// Current implementation of the reading
+fn read_buffered<R: Read>(reader: &mut R, prompt: &Regex) -> String {
+    let (out, _) = ShellReader::new().read_until(reader, prompt, None, &mut |_| {}).unwrap();
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Measures the reading of the output of the specified size and prints its throughput
+fn measure(name: &str, size: usize, read: &Fn(&mut Cursor<Vec<u8>>, &Regex) -> String) {
+    let prompt = Regex::new(PROMPT).unwrap();
+    let data = output(size);
+
+    let mut runs = 0;
+    let mut total = Duration::from_secs(0);
+    while runs < 3 || total < Duration::from_secs(1) && runs < 1000 {
+        let mut reader = Cursor::new(data.clone());
+        let start = Instant::now();
+        let out = read(&mut reader, &prompt);
+        total += start.elapsed();
+        assert_eq!(out.len(), size);
+        runs += 1;
+    }
+
+    let secs = total.as_secs_f64() / runs as f64;
+    println!("{:10} {:>10} bytes  {:>12.3} ms  {:>10.2} MiB/s", name, size, secs * 1000.0, size as f64 / secs / (1024.0 * 1024.0));
+}
+
+fn main() {
+    for size in &[16 * 1024, 64 * 1024, 256 * 1024] {
+        measure("bytewise", *size, &read_bytewise);
+    }
+    for size in &[16 * 1024, 64 * 1024, 256 * 1024, 16 * 1024 * 1024] {
+        measure("buffered", *size, &read_buffered);
+    }
+}
@@ -11,6 +11,9 @@ use state_printer::silent_state_printer::SilentStatePrinter;
 use state_printer::default_state_printer::DefaultStatePrinter;
 use state_printer::state_printer::StatePrinter;
 use script_executor::ScriptConfig;
+use runtime::host_key::{HostKeyCheck, HostKeyVerification};
+use runtime::ssh_config::SshConfig;
+use runtime::rate_limit::parse_rate;
 use out_logger::OutLogger;
 use std::env;
 use std::fs;
@@ -88,6 +91,38 @@ fn main() {
             .long("silent")
             .help("Activates silent mode. In this mode default logging system is disabled. No addition info prints to the stdout except the script itself outputs")
             .required(false))
+        .arg(Arg::with_name("known_hosts")
+            .long("known-hosts")
+            .value_name("FILE")
+            .help("Sets path to the known_hosts file used for the host keys verification. By default '~/.ssh/known_hosts' is used.")
+            .takes_value(true)
+            .required(false))
+        .arg(Arg::with_name("host_key_check")
+            .long("host-key-check")
+            .value_name("POLICY")
+            .help("Sets host key verification policy. 'strict' - host key must be present in the known_hosts file, 'accept-new' - unknown keys are added to the known_hosts file but changed keys are rejected, 'off' - host keys are not verified.")
+            .possible_values(&["strict", "accept-new", "off"])
+            .default_value("accept-new")
+            .takes_value(true)
+            .required(false))
+        .arg(Arg::with_name("ssh_config")
+            .long("ssh-config")
+            .value_name("FILE")
+            .help("Sets path to the OpenSSH client config file. Host aliases, host names, ports, users, identity files, connect timeouts and keepalive intervals from it are applied to the connections. By default '~/.ssh/config' is used if it exists.")
+            .takes_value(true)
+            .required(false))
+        .arg(Arg::with_name("limit_rate")
+            .long("limit-rate")
+            .value_name("RATE")
+            .help("Limits rate of the file transfers in bytes per second. Number may have suffix K, M or G ('512K', '10M'). The rate option of the transfer functions overrides this limit.")
+            .takes_value(true)
+            .required(false))
+        .arg(Arg::with_name("exec_timeout")
+            .long("exec-timeout")
+            .value_name("SECONDS")
+            .help("Sets default timeout of the commands executed in the remote shell. If the prompt does not appear within it, exec returns the timeout error. The exec_timeout option of the connection and the timeout option of exec override this value. By default commands are not limited.")
+            .takes_value(true)
+            .required(false))
         .arg(Arg::from_usage("[arg0] 'optional script argument'"))
         .arg(Arg::from_usage("[arg1] 'optional script argument'"))
         .arg(Arg::from_usage("[arg2] 'optional script argument'"))
@@ -174,6 +209,49 @@ fn main() {
     };
 
 
+    // Read host keys verification settings
+    let host_key_check = HostKeyCheck::parse(matches.value_of("host_key_check").unwrap()).unwrap();
+    let known_hosts = matches.value_of("known_hosts").map(String::from);
+    let host_keys = HostKeyVerification::new(host_key_check, known_hosts);
+
+    // Read ssh config
+    let ssh_config = if matches.value_of("ssh_config").is_some() {
+        SshConfig::load(matches.value_of("ssh_config").unwrap())
+    } else if Path::new(&SshConfig::default_path()).exists() {
+        SshConfig::load(&SshConfig::default_path())
+    } else {
+        Ok(SshConfig::empty())
+    };
+    if ssh_config.is_err() {
+        println!("{}", ssh_config.err().unwrap());
+        return;
+    }
+    let ssh_config = ssh_config.unwrap();
+
+    // Read transfer rate limit
+    let limit_rate = if matches.value_of("limit_rate").is_some() {
+        let rate = parse_rate(matches.value_of("limit_rate").unwrap());
+        if rate.is_err() {
+            println!("{}", rate.err().unwrap());
+            return;
+        }
+        Some(rate.unwrap())
+    } else {
+        None
+    };
+
+    // Read exec timeout
+    let exec_timeout = if matches.value_of("exec_timeout").is_some() {
+        let timeout = matches.value_of("exec_timeout").unwrap().parse::<u64>();
+        if timeout.is_err() {
+            println!("Invalid exec timeout '{}', expected number of seconds", matches.value_of("exec_timeout").unwrap());
+            return;
+        }
+        Some(timeout.unwrap())
+    } else {
+        None
+    };
+
     // Prepare enlivenment and run script
     let out_logger = OutLogger::new(log_file);
     let state_printer: TSafe<StatePrinter + Send> = if matches.index_of("silent").is_none() {
@@ -181,13 +259,20 @@ fn main() {
     } else {
         tsafe!(SilentStatePrinter::new())
     };
+    for warning in ssh_config.warnings() {
+        state_printer.lock().unwrap().warn_current(warning);
+    }
 
     let cfg = ScriptConfig {
         script,
         state_printer,
         out_logger: out_logger.clone(),
         args: script_args,
-        libs
+        libs,
+        host_keys,
+        ssh_config,
+        limit_rate,
+        exec_timeout
     };
 
     out_logger.start_script(&file_path);
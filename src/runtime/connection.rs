@@ -1,49 +1,324 @@
 //! Runtime ssh connection representation
 
 use super::ssh_thread_safe::{ThreadSafeSession, ThreadSafeChannel};
+use super::host_key::HostKeyVerification;
+use super::tunnel::{Tunnel, Forward, LocalForward, RemoteForward};
+use super::proxy_command::ProxyCommand;
+use super::sftp::{SftpSession, SftpOp, SftpValue, SftpError};
+use super::dir_transfer::{TransferStats, DirTransferOptions, send_dir, fetch_dir};
+use super::rate_limit::RateLimiter;
+use super::shell_reader::ShellReader;
+use super::shell_setup::{LOGIN_PROMPT, setup_shell, prompt_status};
+use super::delta::{is_unchanged, send_delta, resume_offset, send_from, local_sha256, remote_sha256, to_hex};
 use crate::state_printer::state_printer::StatePrinter;
 use crate::tsafe::TSafe;
 use crate::out_logger::OutLogger;
 use crate::runtime::script_runtime::BLOCK_SIZE;
-use ssh2::{Session, Channel};
+use ssh2::{Session, Channel, KeyboardInteractivePrompt, Prompt, FileStat};
 use regex::Regex;
+use sha2::{Sha256, Digest};
 use std::sync::{Arc, Mutex};
 use std::net::TcpStream;
 use std::io::prelude::*;
+use std::io::ErrorKind;
 use std::marker::PhantomData;
-use std::time::Duration;
-use std::net::{AddrParseError, SocketAddr};
+use std::time::{Duration, Instant};
+use std::net::{SocketAddr, ToSocketAddrs, Shutdown};
 use std::thread;
 use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{Sender, Receiver, RecvError, RecvTimeoutError};
 use std::io::stdout;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::os::unix::fs::{PermissionsExt, MetadataExt};
 use std::any::Any;
-use std::mem;
+use std::os::unix::net::UnixStream;
 
-pub struct SimpleAuthentication {
+/// Port used if it is not specified in the address
+pub const DEFAULT_SSH_PORT: u16 = 22;
+
+/// Default timeout of the tcp connection establishing in seconds
+pub const CONNECT_TIMEOUT: u64 = 10;
+
+/// Default terminal type of the shell pty
+pub const DEFAULT_TERM: &str = "ansi";
+
+/// Interval of the tunnels polling in milliseconds, when there is no data in them
+const TUNNEL_POLL_INTERVAL: u64 = 5;
+
+/// Interval of the exec channel polling in milliseconds, when there is no data in it
+const RUN_POLL_INTERVAL: u64 = 5;
+
+/// Time in seconds for which the shell must return to the prompt after Ctrl-C sent to the timed
+/// out command
+const INTERRUPT_TIMEOUT: u64 = 5;
+
+/// Transport of the ssh session
+pub enum Transport {
+
+    /// Direct tcp connection to the host
+    Tcp,
+
+    /// Connection through the tunnel opened on the jump host. Contains local end of the tunnel or
+    /// text of the error which was occurred at the tunnel opening
+    Tunnel(Result<UnixStream, String>),
+
+    /// Connection over stdin/stdout of the local process. Contains the process with the stream
+    /// connected to it or text of the error which was occurred at the process starting
+    Command(Result<(ProxyCommand, UnixStream), String>)
+}
+
+/// Parameters of the new connection
+pub struct ConnectionParams {
+
+    /// Address of the remote host in form host[:port]
+    pub addr: String,
+
+    /// Port of the remote host. If specified, it overrides port from the address
+    pub port: Option<u16>,
+
+    /// Remote user name
     pub user: String,
+
+    /// Transport of the session
+    pub transport: Transport,
+
+    /// Authentication methods. Methods are tried in turn until one of them succeeds
+    pub auth: Vec<Box<Any + Send>>,
+
+    /// Shell prompt. None value means that the custom prompt was incorrect
+    pub prompt: Option<Regex>,
+
+    /// The prompt was specified by the script. It is used to wait for the login prompt, otherwise
+    /// the login prompt is recognized by the LOGIN_PROMPT pattern. After the login the prompt is
+    /// replaced by the sentinel in any case, the prompt is used if the sentinel can't be set up.
+    pub custom_prompt: bool,
+
+    /// Timeout of the tcp connection establishing in seconds
+    pub timeout: u64,
+
+    /// Open interactive shell. Connections without shell can't execute commands with exec, they
+    /// are used as jump hosts
+    pub shell: bool,
+
+    /// Terminal type of the shell pty
+    pub term: String,
+
+    /// Interval of the keepalive messages in seconds. If not specified, keepalive messages are not
+    /// sent
+    pub keepalive: Option<u32>,
+
+    /// Default limit of the file transfers rate in bytes per second
+    pub limit_rate: Option<u64>,
+
+    /// Default timeout of the commands executed in the shell in seconds
+    pub exec_timeout: Option<u64>,
+
+    /// Host key verification settings
+    pub host_keys: HostKeyVerification
+}
+
+pub struct SimpleAuthentication {
     pub password: String
 }
 
 pub struct KeyAuthentication {
-    pub user: String,
     pub private_key: String,
     pub public_key: Option<String>,
     pub passphrase: Option<String>
 }
 
+/// Options of the file sending
+#[derive(Default)]
+pub struct SendFileOptions {
+
+    /// Mode of the remote file. If not specified, mode of the local file is used with the
+    /// preserve flag, or 644 otherwise
+    pub mode: Option<i32>,
+
+    /// Copy mode and access/modification times of the local file
+    pub preserve: bool,
+
+    /// Owner of the remote file, user name or uid
+    pub owner: Option<String>,
+
+    /// Group of the remote file, group name or gid
+    pub group: Option<String>,
+
+    /// Do not send the file if the remote file has the same size and sha256 hash
+    pub skip_unchanged: bool,
+
+    /// If the remote file exists, send only blocks which differ from its blocks at the same offsets
+    pub block_delta: bool,
+
+    /// If the remote file exists, continue sending after its part which matches the local file
+    pub resume: bool,
+
+    /// Compare sha256 hash of the sent file with the hash of the remote file after the sending
+    pub verify: bool,
+
+    /// Limit of the transfer rate in bytes per second. If not specified, the default limit of
+    /// the connection is used
+    pub rate: Option<u64>
+}
+
+/// Attributes which was applied to the sent file
+pub struct SendFileReport {
+    pub mode: i32,
+
+    /// Modification and access times
+    pub times: Option<(u64, u64)>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+
+    /// Content was not sent, because the remote file was unchanged
+    pub skipped: bool,
+
+    /// Count of the sent bytes of the content
+    pub transferred: u64,
+
+    /// Offset from which the interrupted sending was continued
+    pub resumed: u64,
+
+    /// Verified sha256 hash of the file
+    pub sha256: Option<String>
+}
+
+/// Options of the command executed in the interactive shell
+#[derive(Default)]
+pub struct ExecOptions {
+
+    /// Time in seconds for which the prompt must appear. If not specified, the default timeout of
+    /// the connection is used. Zero means no timeout.
+    pub timeout: Option<u64>,
+
+    /// Send Ctrl-C to the shell if the command is timed out, to return the shell to the prompt
+    pub interrupt: bool
+}
+
+/// Result of a command executed in the interactive shell
+pub struct ExecResult {
+
+    /// Raw output of the command. If the command failed, it contains output captured before the
+    /// failure
+    pub out: Vec<u8>,
+
+    /// Text of the error occurred while the output was read
+    pub error: Option<String>,
+
+    /// Prompt did not appear within the timeout
+    pub timed_out: bool,
+
+    /// Exit status of the command taken from the sentinel prompt. None if the command failed, or
+    /// if a custom prompt was used
+    pub exit_code: Option<i32>
+}
+
+/// Result of a command executed through a dedicated exec channel
+pub struct RunResult {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+    pub signal: Option<String>
+}
+
+impl RunResult {
+
+    /// Checks that the command exited with zero status. Server does not send the exit status of
+    /// the command terminated by a signal, so such command is not successful regardless of it.
+    pub fn is_success(&self) -> bool {
+        self.exit_code == 0 && self.signal.is_none()
+    }
+}
+
+pub struct AgentAuthentication {}
+
+pub struct InteractiveAuthentication {}
+
+/// Request of the keyboard-interactive authentication from the server
+pub struct InteractivePrompt {
+
+    /// Instructions text from the server
+    pub instructions: String,
+
+    /// Prompts which must be answered. Each prompt has flag, which indicates that answer to it may
+    /// be echoed
+    pub prompts: Vec<(String, bool)>
+}
+
+/// Function which answers to the keyboard-interactive authentication requests. Must return one
+/// answer for each prompt of the request, or text of the error if the answers can't be given. The
+/// error aborts the authentication.
+pub type InteractiveResponder<'a> = Box<FnMut(&InteractivePrompt) -> Result<Vec<String>, String> + 'a>;
+
+/// Passes keyboard-interactive prompts of the server from the connection thread to the connection
+/// creator and waits answers from it
+struct InteractivePrompter<'a> {
+    sender: &'a Sender<CoData>,
+    receiver: &'a Receiver<Result<Vec<String>, String>>,
+
+    /// Closes the transport of the session. Answers to the prompts can't be cancelled, so the
+    /// transport is closed before they are sent, if the responder fails
+    abort: Option<&'a Fn()>,
+
+    /// Error of the responder which aborted the authentication
+    error: Option<String>
+}
+
+impl <'a> KeyboardInteractivePrompt for InteractivePrompter<'a> {
+    fn prompt<'b>(&mut self, _username: &str, instructions: &str, prompts: &[Prompt<'b>]) -> Vec<String> {
+        let request = InteractivePrompt {
+            instructions: String::from(instructions),
+            prompts: prompts.iter().map(|p| (p.text.to_string(), p.echo)).collect()
+        };
+
+        if self.sender.send(CoData::AuthPrompt(request)).is_err() {
+            return Vec::new();
+        }
+
+        match self.receiver.recv() {
+            Ok(Ok(answers)) => answers,
+            Ok(Err(err_t)) => {
+                if self.abort.is_some() {
+                    (self.abort.unwrap())();
+                }
+                self.error = Some(err_t);
+                Vec::new()
+            },
+            Err(_) => Vec::new()
+        }
+    }
+}
+
 enum CoOps {
-    Exec1(String, Option<String>, Option<bool>),
-    SendFile(String, String),
+    Exec1(String, Option<String>, Option<bool>, ExecOptions),
+    Run(String),
+    SendFile(String, String, SendFileOptions),
+    FetchFile(String, String, Option<u64>),
+    SendDir(String, String, DirTransferOptions),
+    FetchDir(String, String, DirTransferOptions),
     SetPrompt(String),
+    OpenTunnel(String, u16),
+    ForwardLocal(u16, String, u16),
+    ForwardRemote(u16, String, u16),
+    ListForwards,
+    CloseForward(usize),
+    Sftp(SftpOp),
     Close
 }
 
 enum CoData {
-    Out(String),
+    Exec(ExecResult),
     BoolResult(bool),
+    RunResult(RunResult),
+    AuthPrompt(InteractivePrompt),
+    Tunnel(UnixStream),
+    Forward(usize, u16),
+    Forwards(Vec<(usize, String)>),
+    Sftp(Result<SftpValue, SftpError>),
+    Transfer(TransferStats),
+    SendReport(SendFileReport),
     Error(String)
 }
 
@@ -57,16 +332,19 @@ pub struct Connection {
 
 impl Connection {
 
-    /// Opens connection to to the specified host and authenticates them with user/password method.
-    pub fn new(addr: String, atk: Box<Any + Send>, state_printer: TSafe<StatePrinter + Send>, out_logger: OutLogger, prompt: Option<Regex>)-> Connection {
-        let mut atk = atk;
+    /// Opens connection to to the specified host and authenticates them with the methods of the
+    /// params. Responder is used for answer to the keyboard-interactive authentication prompts, it
+    /// is called in the current thread.
+    pub fn new(params: ConnectionParams, state_printer: TSafe<StatePrinter + Send>, out_logger: OutLogger, responder: Option<InteractiveResponder>)-> Connection {
+        let mut params = params;
         let (sender, receiver) = mpsc::channel();
         let (r_sender, r_receiver) = mpsc::channel();
+        let (a_sender, a_receiver) = mpsc::channel();
         let mut failed = false;
         let error_o = tsafe!(None);
 
-        let prompt = if prompt.is_some() {
-            prompt.unwrap()
+        let prompt = if params.prompt.is_some() {
+            params.prompt.take().unwrap()
         } else {
             *error_o.lock().unwrap() = Some(String::from("Bad optional prompt"));
             failed = true;
@@ -77,129 +355,246 @@ impl Connection {
         let error = error_o.clone();
 
         thread::spawn(move || {
+            let addr = params.addr;
 
-            match_downcast_mut!(atk, {
-                a: SimpleAuthentication => {
-                    state_printer.lock().unwrap().add_one_line("CONNECT SSH SIMPLE", &format!("{} {}", addr, &a.user));
-                },
-                a: KeyAuthentication => {
-                    state_printer.lock().unwrap().add_one_line("CONNECT SSH KEY", &format!("{} {} {:?}", addr, &a.user, a.private_key));
-                },
-                _ => panic!("Unsupported auth type")
-            });
-
-            let socket_addr: Result<SocketAddr, AddrParseError> = addr.parse();
+            if params.auth.len() == 1 {
+                match_downcast_mut!(params.auth[0], {
+                    _a: SimpleAuthentication => {
+                        state_printer.lock().unwrap().add_one_line("CONNECT SSH SIMPLE", &format!("{} {}", addr, &params.user));
+                    },
+                    a: KeyAuthentication => {
+                        state_printer.lock().unwrap().add_one_line("CONNECT SSH KEY", &format!("{} {} {:?}", addr, &params.user, a.private_key));
+                    },
+                    _a: AgentAuthentication => {
+                        state_printer.lock().unwrap().add_one_line("CONNECT SSH AGENT", &format!("{} {}", addr, &params.user));
+                    },
+                    _a: InteractiveAuthentication => {
+                        state_printer.lock().unwrap().add_one_line("CONNECT SSH INTERACTIVE", &format!("{} {}", addr, &params.user));
+                    },
+                    _ => panic!("Unsupported auth type")
+                });
+            } else {
+                let methods: Vec<&str> = params.auth.iter().map(|a| Self::auth_name(a)).collect();
+                state_printer.lock().unwrap().add_one_line("CONNECT SSH", &format!("{} {} [{}]", addr, &params.user, methods.join(", ")));
+            }
 
-            if socket_addr.is_err() {
-                let err_t = format!("Invalid ip address: {}", socket_addr.err().unwrap());
+            let host_port = Self::split_addr(&addr);
+            if host_port.is_err() {
+                let err_t = host_port.err().unwrap();
                 state_printer.lock().unwrap().error_current(&err_t);
                 Self::err_conn(&state_printer, error, err_t);
                 r_sender.send(CoData::BoolResult(false));
                 return;
             }
-            let socket_addr = socket_addr.unwrap();
+            let (host, port) = host_port.unwrap();
+            let port = params.port.or(port).unwrap_or(DEFAULT_SSH_PORT);
 
-            let tcp = TcpStream::connect_timeout(&socket_addr, Duration::from_secs(10));
-            if tcp.is_err() {
-                let err_t = format!("Tcp connection error: {}", tcp.err().unwrap());
+            let session = Session::new();
+            if session.is_err() {
+                let err_t = format!("Unable to initialize ssh session: {}", session.err().unwrap());
                 state_printer.lock().unwrap().error_current(&err_t);
                 Self::err_conn(&state_printer,error, err_t);
                 r_sender.send(CoData::BoolResult(false));
                 return;
             }
-            let tcp = tcp.unwrap();
+            let mut  session = session.unwrap();
+            //session.set_timeout(5000);
+            if params.keepalive.is_some() {
+                session.set_keepalive(false, params.keepalive.unwrap());
+            }
 
-            let session = Session::new();
-            if session.is_none() {
-                let err_t = format!("Unable to initialize ssh session");
+            // Proxy process lives as long as the connection thread
+            let mut proxy: Option<ProxyCommand> = None;
+            let mut abort: Option<Box<Fn()>> = None;
+            let transport_result = match params.transport {
+                Transport::Tcp => {
+                    Self::connect_tcp(&host, port, params.timeout).map(|tcp| {
+                        abort = Self::tcp_abort(&tcp);
+                        session.set_tcp_stream(tcp)
+                    })
+                },
+                Transport::Tunnel(stream) => {
+                    stream.map(|stream| {
+                        abort = Self::unix_abort(&stream);
+                        session.set_tcp_stream(stream)
+                    })
+                },
+                Transport::Command(command) => {
+                    command.map(|(command, stream)| {
+                        proxy = Some(command);
+                        abort = Self::unix_abort(&stream);
+                        session.set_tcp_stream(stream)
+                    })
+                }
+            };
+            if transport_result.is_err() {
+                let err_t = transport_result.err().unwrap();
                 state_printer.lock().unwrap().error_current(&err_t);
                 Self::err_conn(&state_printer,error, err_t);
                 r_sender.send(CoData::BoolResult(false));
                 return;
             }
-            let mut  session = session.unwrap();
-            //session.set_timeout(5000);
 
-            let handshake_result = session.handshake(&tcp);
+            // The proxy command may hang, so the handshake through it is limited by the timeout
+            if proxy.is_some() {
+                session.set_timeout((params.timeout * 1000) as u32);
+            }
+            let handshake_result = session.handshake();
+            session.set_timeout(0);
             if handshake_result.is_err() {
-                let err_t = format!("Handshake error: {}", handshake_result.err().unwrap());
+                let mut err_t = format!("Handshake error: {}", handshake_result.err().unwrap());
+                if proxy.is_some() {
+                    let output = proxy.as_ref().unwrap().error_output();
+                    if !output.is_empty() {
+                        err_t = format!("{}, proxy command output: {}", err_t, output);
+                    }
+                }
                 state_printer.lock().unwrap().error_current(&err_t);
                 Self::err_conn(&state_printer,error, err_t);
                 r_sender.send(CoData::BoolResult(false));
                 return;
             }
 
-            match_downcast_mut!(atk, {
-                a: SimpleAuthentication => {
-                    let auth_result = session.userauth_password(&a.user, &a.password);
-                    if auth_result.is_err() {
-                        let err_t = format!("Authentication error: {}", auth_result.err().unwrap());
-                        state_printer.lock().unwrap().error_current(&err_t);
-                        Self::err_conn(&state_printer,error, err_t);
-                        r_sender.send(CoData::BoolResult(false));
-                        return;
-                    }
-                },
-                a: KeyAuthentication => {
-                    let public_key = mem::replace(&mut a.public_key, None);
-                    let public_key  = if public_key.is_some() {
-                        Some(Path::new(public_key.as_ref().unwrap()))
-                    } else {
-                        None
-                    };
+            let verify_result = params.host_keys.verify(&session, &host, port, &mut |warning| {
+                state_printer.lock().unwrap().warn_current(warning);
+            });
+            if verify_result.is_err() {
+                let err_t = verify_result.err().unwrap();
+                state_printer.lock().unwrap().error_current(&err_t);
+                Self::err_conn(&state_printer,error, err_t);
+                r_sender.send(CoData::BoolResult(false));
+                return;
+            }
+            if let Some(notice) = verify_result.unwrap() {
+                state_printer.lock().unwrap().print_to_current(&notice);
+            }
 
-                     let public_key: Option<&Path>  = if public_key.is_some() {
-                        Some(public_key.as_ref().unwrap())
-                    } else {
-                        None
-                    };
+            // Try authentication methods in turn
+            let mut prompter = InteractivePrompter {
+                sender: &r_sender,
+                receiver: &a_receiver,
+                abort: abort.as_ref().map(|a| &**a),
+                error: None
+            };
+            let methods_count = params.auth.len();
+            let mut auth_errors = Vec::new();
+            for atk in params.auth.iter_mut() {
+                let auth_result = Self::authenticate(&session, &params.user, atk, &mut prompter);
+                if auth_result.is_ok() && session.authenticated() {
+                    break;
+                }
 
-                    let passphrase = mem::replace(&mut a.passphrase, None);
-                    let passphrase: Option<&str> = if passphrase.is_some() {
-                        Some(&passphrase.as_ref().unwrap()[..])
-                    } else {
-                        None
-                    };
+                // Transport is closed by the failed responder, other methods can't be tried
+                let aborted = prompter.error.is_some();
+                let err_t = if aborted {
+                    format!("aborted by the prompt callback: {}", prompter.error.take().unwrap())
+                } else {
+                    auth_result.err().unwrap_or(String::from("not authenticated"))
+                };
+                if methods_count > 1 {
+                    auth_errors.push(format!("{} - {}", Self::auth_name(atk), err_t));
+                } else {
+                    auth_errors.push(err_t);
+                }
+                if aborted {
+                    break;
+                }
+            }
 
-                    let private_key = Path::new(&a.private_key);
-                    let auth_result = session.userauth_pubkey_file(&a.user, public_key, &private_key, passphrase);
-                    if auth_result.is_err() {
-                        let err_t = format!("Authentication error: {}", auth_result.err().unwrap());
-                        state_printer.lock().unwrap().error_current(&err_t);
-                        Self::err_conn(&state_printer,error, err_t);
-                        r_sender.send(CoData::BoolResult(false));
-                        return;
-                    }
-                },
-                _ => panic!("Unsupported auth type")
-            });
+            if !session.authenticated() {
+                let err_t = if auth_errors.is_empty() {
+                    String::from("Authentication error: no authentication methods specified")
+                } else {
+                    format!("Authentication error: {}", auth_errors.join("; "))
+                };
+                state_printer.lock().unwrap().error_current(&err_t);
+                Self::err_conn(&state_printer,error, err_t);
+                r_sender.send(CoData::BoolResult(false));
+                return;
+            }
 
             // Default prompt
             let mut prompt = prompt;
 
-            // Create shell
-            let mut shell: Channel = session.channel_session().unwrap();
-            shell.request_pty("ansi", None, None);
-            shell.request_pty_size(100, 100, None, None);
-            shell.shell().unwrap();
+            // Default prompt is the sentinel, which carries exit status of the last command
+            let mut prompt_has_status = false;
 
-            // Read  out to the first prompt
-            let out = Self::read_out(&mut shell, &prompt, true, 0);
+            // Create shell
+            let mut shell: Option<Channel> = None;
+            let mut reader = ShellReader::new();
+            if params.shell {
+                let mut channel: Channel = session.channel_session().unwrap();
+                channel.request_pty(&params.term, None, None);
+                channel.request_pty_size(100, 100, None, None);
+                channel.shell().unwrap();
+
+                // Wait for the login prompt and replace it by the sentinel of the session. If the
+                // shell does not accept it (restricted shells, network appliances), the prompt of
+                // the script or the default one is used further
+                let login_prompt = if params.custom_prompt { prompt.clone() } else { Regex::new(LOGIN_PROMPT).unwrap() };
+                let setup_result = setup_shell(&mut channel, &mut reader, &login_prompt, params.timeout, &mut |remaining| {
+                    // Zero timeout of the session means infinite waiting
+                    session.set_timeout(std::cmp::max(remaining.as_millis() as u32, 1));
+                });
+                session.set_timeout(0);
+                match setup_result {
+                    Ok((_, sentinel_prompt)) => {
+                        prompt = sentinel_prompt;
+                        prompt_has_status = true;
+                    },
+                    Err(err_t) => {
+                        state_printer.lock().unwrap().warn_current(&format!("{}, prompt '{}' is used", err_t, prompt.as_str()));
+                    }
+                }
 
-            // Setup new default prompt
-             write!(shell, "PS1=qwerty\n");
-             prompt = Regex::new("qwerty").unwrap();
-             let out = Self::read_out(&mut shell, &prompt, false, 0);
-             let out = Self::read_out(&mut shell, &prompt, false, 0);
-             //println!("out = {}", out);
+                shell = Some(channel);
+            }
 
             r_sender.send(CoData::BoolResult(true));
 
             //println!("out = {}", out);
             stdout().flush();
+            // Tunnels and port forwardings served by this connection and flag which indicates that
+            // some data was passed through them at the last poll
+            let mut tunnels: Vec<Tunnel> = Vec::new();
+            let mut forwards: Vec<Box<Forward>> = Vec::new();
+            let mut forwards_counter = 0;
+            let mut tunnels_busy = false;
+
+            // Sftp subsystem, it is opened at the first sftp operation
+            let mut sftp: Option<SftpSession> = None;
             loop {
 
-                let action = receiver.recv();
+                let polling = !tunnels.is_empty() || !forwards.is_empty();
+                let action = if polling || params.keepalive.is_some() {
+                    let interval = if tunnels_busy {
+                        Duration::from_millis(0)
+                    } else if polling {
+                        Duration::from_millis(TUNNEL_POLL_INTERVAL)
+                    } else {
+                        Duration::from_secs(params.keepalive.unwrap() as u64)
+                    };
+                    match receiver.recv_timeout(interval) {
+                        Ok(action) => Ok(action),
+                        Err(RecvTimeoutError::Timeout) => {
+                            if polling {
+                                tunnels_busy = false;
+                                for forward in forwards.iter_mut() {
+                                    tunnels_busy |= forward.accept(&session);
+                                    tunnels_busy |= Self::pump_tunnels(&session, forward.tunnels());
+                                }
+                                tunnels_busy |= Self::pump_tunnels(&session, &mut tunnels);
+                            }
+                            if params.keepalive.is_some() {
+                                session.keepalive_send();
+                            }
+                            continue;
+                        },
+                        Err(RecvTimeoutError::Disconnected) => Err(RecvError)
+                    }
+                } else {
+                    receiver.recv()
+                };
                 if action.is_err() {
                     println!("{}", &action.err().unwrap());
                     return
@@ -207,7 +602,7 @@ impl Connection {
 
                 let action = action.unwrap();
                 match action {
-                    CoOps::Exec1(cmd, custom_prompt, with_prompt) => {
+                    CoOps::Exec1(cmd, custom_prompt, with_prompt, options) => {
                         let mut state_printer = state_printer.lock().unwrap();
 
                         let mut pstr = String::from(&cmd[..]);
@@ -219,6 +614,14 @@ impl Connection {
                         }
                         state_printer.add_one_line("EXEC",&pstr);
 
+                        if shell.is_none() {
+                            let err_text = String::from("Connection has no interactive shell");
+                            state_printer.error_current(&err_text);
+                            r_sender.send(CoData::Error(err_text));
+                            continue;
+                        }
+                        let shell = shell.as_mut().unwrap();
+
                         write!(shell, "{}\n", cmd);
 
                         let with_prompt = {
@@ -229,6 +632,9 @@ impl Connection {
                             }
                         };
 
+                        let timeout = options.timeout.or(params.exec_timeout).filter(|t| *t > 0);
+
+                        let has_status = custom_prompt.is_none() && prompt_has_status;
                         let out = if custom_prompt.is_some() {
                             let cp = custom_prompt.unwrap();
                             let custom_prompt = Regex::new(&cp);
@@ -239,12 +645,60 @@ impl Connection {
                             }
                             let custom_prompt = custom_prompt.unwrap();
 
-                            Self::read_out(&mut shell, &custom_prompt, with_prompt, cmd.len() + 2)
+                            Self::read_out(&session, shell, &mut reader, &custom_prompt, with_prompt, cmd.len() + 2, timeout)
                         } else {
-                            Self::read_out(&mut shell, &prompt, with_prompt, cmd.len() + 2)
+                            Self::read_out(&session, shell, &mut reader, &prompt, with_prompt, cmd.len() + 2, timeout)
                         };
 
-                        r_sender.send(CoData::Out(out));
+                        let result = match out {
+                            Ok((out, matched)) => {
+                                let exit_code = if has_status { prompt_status(&prompt, &matched) } else { None };
+                                ExecResult { out, error: None, timed_out: false, exit_code }
+                            },
+                            Err((mut out, kind)) => {
+                                let timed_out = kind == ErrorKind::TimedOut;
+                                let mut err_text = if timed_out {
+                                    format!("Command timed out after {} s", timeout.unwrap())
+                                } else {
+                                    String::from("Shell channel is closed")
+                                };
+
+                                // Shell is returned to the default prompt, output after Ctrl-C is
+                                // added to the captured output
+                                if timed_out && options.interrupt {
+                                    write!(shell, "\x03");
+                                    match Self::read_out(&session, shell, &mut reader, &prompt, false, 0, Some(INTERRUPT_TIMEOUT)) {
+                                        Ok((rest, _)) => {
+                                            out.extend_from_slice(&rest);
+                                            err_text.push_str(", interrupted by Ctrl-C");
+                                        },
+                                        Err((rest, _)) => {
+                                            out.extend_from_slice(&rest);
+                                            err_text.push_str(", shell did not return to the prompt after Ctrl-C");
+                                        }
+                                    }
+                                }
+
+                                state_printer.error_current(&err_text);
+                                ExecResult { out, error: Some(err_text), timed_out, exit_code: None }
+                            }
+                        };
+
+                        r_sender.send(CoData::Exec(result));
+                    },
+                    CoOps::Run(cmd) => {
+                        let mut state_printer = state_printer.lock().unwrap();
+                        state_printer.add_one_line("RUN", &cmd);
+
+                        match Self::run_command(&session, &cmd) {
+                            Ok(result) => {
+                                r_sender.send(CoData::RunResult(result));
+                            },
+                            Err(err_text) => {
+                                state_printer.error_current(&err_text);
+                                r_sender.send(CoData::Error(err_text));
+                            }
+                        }
                     },
                     CoOps::SetPrompt(pattern) => {
                         let mut state_printer = state_printer.lock().unwrap();
@@ -252,85 +706,146 @@ impl Connection {
                         let np = Regex::new(&pattern);
                         if np.is_ok() {
                             prompt = np.unwrap();
+                            prompt_has_status = false;
                             r_sender.send(CoData::BoolResult(true));
                         } else {
                             state_printer.error_current(&format!("Incorrect prompt regexp '{}'", &pattern));
                             r_sender.send(CoData::BoolResult(false));
                         }
                     },
-                    CoOps::SendFile(source, dest) => {
+                    CoOps::SendFile(source, dest, mut options) => {
                         let mut state_printer = state_printer.lock().unwrap();
+                        options.rate = options.rate.or(params.limit_rate);
 
-                        let file = File::open(&source);
-                        if file.is_err() {
-                            let error = file.err().unwrap();
-                            state_printer.add_one_line("SEND FILE",&format!("{} -> {}", &source, &dest));
-                            let err_text = format!("Unable to open source file: {}", error);
-                            state_printer.error_current(&err_text);
-                            r_sender.send(CoData::Error(err_text));
-                            continue;
-                        }
-                        let mut file = file.unwrap();
-
-                        let f_meta = file.metadata().unwrap();
-                        let f_size = f_meta.len();
-
-                        let mut remote_file = session.scp_send(Path::new(&dest),
-                                                               0o644, f_size, None);
-                        if remote_file.is_err() {
-                            let error = remote_file.err().unwrap();
-                            state_printer.add_one_line("SEND FILE",&format!("{} -> {}", &source, &dest));
-                            let err_text = format!("Unable to open dest file: {}", &error);
-                            state_printer.error_current(&err_text);
-                            r_sender.send(CoData::Error(err_text));
-                            continue;
+                        match Self::transmit_file(&session, &mut sftp, &mut *state_printer, &source, &dest, &options) {
+                            Ok(report) => {
+                                state_printer.set_progress(100.0);
+                                state_printer.complete_current();
+                                r_sender.send(CoData::SendReport(report));
+                            },
+                            Err(err_text) => {
+                                state_printer.error_current(&err_text);
+                                r_sender.send(CoData::Error(err_text));
+                            }
                         }
-                        let mut remote_file = remote_file.unwrap();
-
-
-
-                        let p_size = (f_size as f64 / BLOCK_SIZE as f64).ceil();
-
-                        let mut buf = [0; BLOCK_SIZE];
-
-                        state_printer.add_progress("SEND FILE",&format!("{} -> {}", &source, &dest), &format!("{}/0", f_size));
+                    },
+                    CoOps::FetchFile(source, dest, rate) => {
+                        let mut state_printer = state_printer.lock().unwrap();
+                        let rate = rate.or(params.limit_rate);
 
-                        for i in 0..(p_size as u64) {
-                            let read = file.read(&mut buf);
-                            if read.is_err() {
-                                let error = read.err().unwrap();
-                                let err_text = format!("Unable to read source file: {}", &error);
-                                state_printer.error_current(&err_text);
+                        match Self::receive_file(&session, &mut *state_printer, &source, &dest, rate) {
+                            Ok(()) => {
+                                state_printer.set_progress(100.0);
                                 state_printer.complete_current();
+                                r_sender.send(CoData::BoolResult(true));
+                            },
+                            Err(err_text) => {
+                                state_printer.error_current(&err_text);
                                 r_sender.send(CoData::Error(err_text));
-                                continue;
                             }
-                            let read = read.unwrap();
-                            let writed = remote_file.write(&buf[0..read]);
-                            if writed.is_err() {
-                                let error = writed.err().unwrap();
-                                let err_text = format!("Unable to write dest file: {}", &error);
-                                state_printer.error_current(&err_text);
-                                state_printer.complete_current();
+                        }
+                    },
+                    CoOps::OpenTunnel(host, port) => {
+                        state_printer.lock().unwrap().add_one_line("OPEN TUNNEL", &format!("{}:{}", &host, port));
+                        match Self::create_tunnel(&session, &host, port) {
+                            Ok((tunnel, stream)) => {
+                                tunnels.push(tunnel);
+                                r_sender.send(CoData::Tunnel(stream));
+                            },
+                            Err(err_text) => {
+                                state_printer.lock().unwrap().error_current(&err_text);
                                 r_sender.send(CoData::Error(err_text));
                             }
-
-                            remote_file.flush();
-                            state_printer.update_bar_title(&format!("{}/{}", f_size, BLOCK_SIZE * i as usize + read));
-                            let percent = (i as f32 / (p_size as f32 / 100.0));
-                            state_printer.set_progress(percent);
                         }
-
-                        state_printer.set_progress(100.0);
-                        state_printer.complete_current();
+                    },
+                    CoOps::ForwardLocal(local_port, host, port) => {
+                        state_printer.lock().unwrap().add_one_line("FORWARD LOCAL", &format!("{} -> {}:{}", local_port, &host, port));
+                        forwards_counter += 1;
+                        match LocalForward::bind(forwards_counter, local_port, host, port) {
+                            Ok((forward, bound_port)) => {
+                                forwards.push(Box::new(forward));
+                                r_sender.send(CoData::Forward(forwards_counter, bound_port));
+                            },
+                            Err(err_text) => {
+                                state_printer.lock().unwrap().error_current(&err_text);
+                                r_sender.send(CoData::Error(err_text));
+                            }
+                        }
+                    },
+                    CoOps::ForwardRemote(remote_port, host, port) => {
+                        state_printer.lock().unwrap().add_one_line("FORWARD REMOTE", &format!("{} -> {}:{}", remote_port, &host, port));
+                        forwards_counter += 1;
+                        match RemoteForward::listen(&session, forwards_counter, remote_port, host, port) {
+                            Ok((forward, bound_port)) => {
+                                forwards.push(Box::new(forward));
+                                r_sender.send(CoData::Forward(forwards_counter, bound_port));
+                            },
+                            Err(err_text) => {
+                                state_printer.lock().unwrap().error_current(&err_text);
+                                r_sender.send(CoData::Error(err_text));
+                            }
+                        }
+                    },
+                    CoOps::ListForwards => {
+                        let list = forwards.iter().map(|f| (f.id(), f.describe())).collect();
+                        r_sender.send(CoData::Forwards(list));
+                    },
+                    CoOps::CloseForward(id) => {
+                        forwards.retain(|f| f.id() != id);
                         r_sender.send(CoData::BoolResult(true));
                     },
+                    CoOps::Sftp(op) => {
+                        if let Some((title, body)) = SftpSession::describe(&op) {
+                            state_printer.lock().unwrap().add_one_line(title, &body);
+                        }
+
+                        match Self::open_sftp(&session, &mut sftp) {
+                            Ok(sftp) => {
+                                r_sender.send(CoData::Sftp(sftp.execute(op)));
+                            },
+                            Err(err_text) => {
+                                state_printer.lock().unwrap().error_current(&err_text);
+                                r_sender.send(CoData::Sftp(Err(SftpError { code: None, text: err_text })));
+                            }
+                        }
+                    },
+                    CoOps::SendDir(source, dest, mut options) => {
+                        let mut state_printer = state_printer.lock().unwrap();
+                        options.rate = options.rate.or(params.limit_rate);
+                        let result = Self::open_sftp(&session, &mut sftp)
+                            .and_then(|sftp| send_dir(&session, sftp.sftp(), &mut *state_printer, &source, &dest, &options));
+                        Self::complete_transfer(&mut *state_printer, &r_sender, result);
+                    },
+                    CoOps::FetchDir(source, dest, mut options) => {
+                        let mut state_printer = state_printer.lock().unwrap();
+                        options.rate = options.rate.or(params.limit_rate);
+                        let result = Self::open_sftp(&session, &mut sftp)
+                            .and_then(|sftp| fetch_dir(&session, sftp.sftp(), &mut *state_printer, &source, &dest, &options));
+                        Self::complete_transfer(&mut *state_printer, &r_sender, result);
+                    },
                     CoOps::Close => return
                 }
             }
         });
 
-        r_receiver.recv();
+        // Wait connection result, answering to the authentication prompts along the way
+        let mut responder = responder;
+        loop {
+            match r_receiver.recv() {
+                Ok(CoData::AuthPrompt(request)) => {
+                    let answers = if responder.is_some() {
+                        (responder.as_mut().unwrap())(&request)
+                    } else {
+                        Ok(Vec::new())
+                    };
+                    a_sender.send(answers.map(|mut answers| {
+                        answers.resize(request.prompts.len(), String::new());
+                        answers
+                    }));
+                },
+                _ => break
+            }
+        }
 
         Connection {
             sender,
@@ -339,6 +854,163 @@ impl Connection {
         }
     }
 
+    /// Splits address to the host and port parts. Address may be specified as 'host', 'host:port',
+    /// '[ipv6]' or '[ipv6]:port'. Plain ipv6 address without brackets is accepted too, but port
+    /// can't be specified with it. Port is None if it is omitted.
+    pub fn split_addr(addr: &str) -> Result<(String, Option<u16>), String> {
+        let addr = addr.trim();
+
+        let (host, port) = if addr.starts_with('[') {
+            let end = addr.find(']');
+            if end.is_none() {
+                return Err(format!("Invalid address '{}': unclosed bracket", addr));
+            }
+            let end = end.unwrap();
+            let rest = &addr[end + 1..];
+            let port = if rest.is_empty() {
+                None
+            } else if rest.starts_with(':') {
+                Some(&rest[1..])
+            } else {
+                return Err(format!("Invalid address '{}': unexpected text after bracket", addr));
+            };
+            (&addr[1..end], port)
+        } else if addr.matches(':').count() == 1 {
+            let sep = addr.find(':').unwrap();
+            (&addr[..sep], Some(&addr[sep + 1..]))
+        } else {
+            (addr, None)
+        };
+
+        if host.is_empty() {
+            return Err(format!("Invalid address '{}': empty host", addr));
+        }
+
+        let port = if port.is_some() {
+            let port = port.unwrap().parse::<u16>();
+            if port.is_err() {
+                return Err(format!("Invalid address '{}': bad port - {}", addr, port.err().unwrap()));
+            }
+            Some(port.unwrap())
+        } else {
+            None
+        };
+
+        Ok((String::from(host), port))
+    }
+
+    /// Internal API - resolves host and opens tcp connection to it. Each of the resolved socket
+    /// addresses is tried in turn until connection is established.
+    fn connect_tcp(host: &str, port: u16, timeout: u64) -> Result<TcpStream, String> {
+        let socket_addrs = (host, port).to_socket_addrs();
+        if socket_addrs.is_err() {
+            return Err(format!("Unable to resolve host '{}': {}", host, socket_addrs.err().unwrap()));
+        }
+        let socket_addrs: Vec<SocketAddr> = socket_addrs.unwrap().collect();
+        if socket_addrs.is_empty() {
+            return Err(format!("Unable to resolve host '{}': no addresses found", host));
+        }
+
+        let mut errors = Vec::new();
+        for socket_addr in socket_addrs {
+            match TcpStream::connect_timeout(&socket_addr, Duration::from_secs(timeout)) {
+                Ok(tcp) => return Ok(tcp),
+                Err(e) => errors.push(format!("{} - {}", socket_addr, e))
+            }
+        }
+
+        Err(format!("Tcp connection error: {}", errors.join(", ")))
+    }
+
+    /// Internal API - returns function which closes the tcp transport of the session
+    fn tcp_abort(stream: &TcpStream) -> Option<Box<Fn()>> {
+        let stream = stream.try_clone().ok()?;
+        Some(Box::new(move || { stream.shutdown(Shutdown::Both); }))
+    }
+
+    /// Internal API - returns function which closes the tunnel or proxy command transport of the
+    /// session
+    fn unix_abort(stream: &UnixStream) -> Option<Box<Fn()>> {
+        let stream = stream.try_clone().ok()?;
+        Some(Box::new(move || { stream.shutdown(Shutdown::Both); }))
+    }
+
+    /// Internal API - authenticates session with the method defined by type of the atk
+    fn authenticate(session: &Session, user: &str, atk: &mut Box<Any + Send>, prompter: &mut InteractivePrompter) -> Result<(), String> {
+        match_downcast_mut!(atk, {
+            a: SimpleAuthentication => {
+                session.userauth_password(user, &a.password).map_err(|e| e.to_string())
+            },
+            a: KeyAuthentication => {
+                let public_key = a.public_key.as_ref().map(Path::new);
+                let passphrase = a.passphrase.as_ref().map(|p| &p[..]);
+                let private_key = Path::new(&a.private_key);
+                session.userauth_pubkey_file(user, public_key, &private_key, passphrase).map_err(|e| e.to_string())
+            },
+            _a: InteractiveAuthentication => {
+                session.userauth_keyboard_interactive(user, prompter).map_err(|e| e.to_string())
+            },
+            _a: AgentAuthentication => {
+                Self::agent_auth(session, user)
+            },
+            _ => panic!("Unsupported auth type")
+        })
+    }
+
+    /// Internal API - returns short name of the authentication method defined by type of the atk
+    fn auth_name(atk: &Box<Any + Send>) -> &'static str {
+        match_downcast_ref!(atk, {
+            _a: SimpleAuthentication => "password",
+            _a: KeyAuthentication => "key",
+            _a: InteractiveAuthentication => "interactive",
+            _a: AgentAuthentication => "agent",
+            _ => "unknown"
+        })
+    }
+
+    /// Internal API - authenticates session with identities offered by the ssh agent. Each
+    /// identity is tried in turn until one of them is accepted by the server.
+    fn agent_auth(session: &Session, user: &str) -> Result<(), String> {
+        let agent = session.agent();
+        if agent.is_err() {
+            return Err(format!("unable to initialize ssh agent: {}", agent.err().unwrap()));
+        }
+        let mut agent = agent.unwrap();
+
+        let connect_result = agent.connect();
+        if connect_result.is_err() {
+            return Err(format!("unable to connect to ssh agent (is SSH_AUTH_SOCK set?): {}", connect_result.err().unwrap()));
+        }
+
+        let list_result = agent.list_identities();
+        if list_result.is_err() {
+            agent.disconnect();
+            return Err(format!("unable to list ssh agent identities: {}", list_result.err().unwrap()));
+        }
+
+        let identities = agent.identities();
+        if identities.is_err() {
+            agent.disconnect();
+            return Err(format!("unable to get ssh agent identities: {}", identities.err().unwrap()));
+        }
+
+        let mut tried = Vec::new();
+        for identity in identities.unwrap() {
+            if agent.userauth(user, &identity).is_ok() {
+                agent.disconnect();
+                return Ok(());
+            }
+            tried.push(String::from(identity.comment()));
+        }
+
+        agent.disconnect();
+        if tried.is_empty() {
+            Err(String::from("ssh agent has no identities"))
+        } else {
+            Err(format!("no ssh agent identity was accepted, tried: {}", tried.join(", ")))
+        }
+    }
+
     /// Internal API - sets error of the connection
     fn err_conn(state_printer: &TSafe<StatePrinter + Send>, err: TSafe<Option<String>>, err_t: String) {
         state_printer.lock().unwrap().error_current(&err_t);
@@ -346,87 +1018,513 @@ impl Connection {
         *err = Some(err_t);
     }
 
-    /// Internal API - reads out from the shell
-    fn read_out(shell: &mut Channel, prompt: &Regex, with_prompt: bool, fs: usize) -> String {
-        // Read output to the first prompt
-        let mut out = String::new();
-        let mut inter_buf = Vec::new();
+    /// Internal API - reads out from the shell to the first prompt. First fs bytes of the output
+    /// (echo of the command) are skipped. If the timeout in seconds is specified and the prompt
+    /// does not appear within it, or if the shell channel fails, the output read so far is returned
+    /// with the kind of the error. Output is returned as raw bytes, it is decoded by the caller.
+    /// Matched prompt is returned with the output.
+    fn read_out(session: &Session, shell: &mut Channel, reader: &mut ShellReader, prompt: &Regex, with_prompt: bool, fs: usize, timeout: Option<u64>) -> Result<(Vec<u8>, Vec<u8>), (Vec<u8>, ErrorKind)> {
+        let deadline = timeout.map(|t| Instant::now() + Duration::from_secs(t));
+        let result = reader.read_until(shell, prompt, deadline, &mut |remaining| {
+            // Zero timeout of the session means infinite waiting
+            session.set_timeout(std::cmp::max(remaining.as_millis() as u32, 1));
+        });
+        if deadline.is_some() {
+            session.set_timeout(0);
+        }
+
+        let (mut out, matched, kind) = match result {
+            Ok((mut out, matched)) => {
+                if with_prompt {
+                    out.extend_from_slice(&matched);
+                }
+                (out, matched, None)
+            },
+            Err(err) => (reader.take_pending(), Vec::new(), Some(err.kind()))
+        };
+
+        let fs = std::cmp::min(fs, out.len());
+        let out = out.split_off(fs);
+        match kind {
+            None => Ok((out, matched)),
+            Some(kind) => Err((out, kind))
+        }
+    }
+
+    /// Internal API - executes command in a separate non-pty exec channel and collects its
+    /// outputs and exit status
+    pub fn run_command(session: &Session, cmd: &str) -> Result<RunResult, String> {
+        let channel = session.channel_session();
+        if channel.is_err() {
+            return Err(format!("Unable to open exec channel: {}", channel.err().unwrap()));
+        }
+        let mut channel = channel.unwrap();
+
+        let exec_result = channel.exec(cmd);
+        if exec_result.is_err() {
+            return Err(format!("Unable to execute command: {}", exec_result.err().unwrap()));
+        }
+
+        // Both streams share the window of the channel, so they are read in turn. Otherwise the
+        // command may block on the full stderr while stdout is read to the end.
+        session.set_blocking(false);
+        let read_result = Self::read_channel(&mut channel);
+        session.set_blocking(true);
+        if read_result.is_err() {
+            return Err(read_result.err().unwrap());
+        }
+        let (stdout, stderr) = read_result.unwrap();
+
+        let close_result = channel.wait_close();
+        if close_result.is_err() {
+            return Err(format!("Unable to close exec channel: {}", close_result.err().unwrap()));
+        }
+
+        let exit_code = channel.exit_status();
+        if exit_code.is_err() {
+            return Err(format!("Unable to get exit status of the command: {}", exit_code.err().unwrap()));
+        }
+        let exit_code = exit_code.unwrap();
+        let signal = channel.exit_signal().ok().and_then(|s| s.exit_signal);
+
+        Ok(RunResult {
+            exit_code,
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            signal
+        })
+    }
+
+    /// Internal API - reads stdout and stderr of the exec channel up to the eof. Session must be in
+    /// the non-blocking mode.
+    fn read_channel(channel: &mut Channel) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
         loop {
-            let mut buf = vec![0; 1];
-            let read_result = shell.read_exact(&mut buf).unwrap();
-            inter_buf.push(buf[0]);
-            let utf8 = std::str::from_utf8(&buf);
-
-            if utf8.is_ok() {
-                out.push_str(&utf8.unwrap());
-                let pf = prompt.find(&out);
-                if pf.is_some() {
-                   // println!("out_int = {}", &out);
-                    if !with_prompt {
-                        let pf = pf.unwrap();
-                        let len = pf.end() - pf.start();
-                        out = String::from(&out[fs..out.len() - len])
-                    } else {
-                        out = String::from(&out[fs..out.len()])
-                    }
+            let out_read = Self::read_available(channel, &mut stdout);
+            if out_read.is_err() {
+                return Err(format!("Unable to read command stdout: {}", out_read.err().unwrap()));
+            }
+
+            let err_read = Self::read_available(&mut channel.stderr(), &mut stderr);
+            if err_read.is_err() {
+                return Err(format!("Unable to read command stderr: {}", err_read.err().unwrap()));
+            }
+
+            if out_read.unwrap() + err_read.unwrap() == 0 {
+                if channel.eof() {
                     break;
                 }
-
-                inter_buf.clear();
+                thread::sleep(Duration::from_millis(RUN_POLL_INTERVAL));
             }
         }
 
-        out
+        Ok((stdout, stderr))
     }
 
-    /// Execute shell command on the remote server. Returns error flag and output of the executed
-    /// command.
-    pub fn exec(&mut self, cmd: String, prompt: Option<String>, with_prompt: Option<bool>) -> (bool, String) {
-        let sr = self.sender.send(CoOps::Exec1(cmd, prompt, with_prompt));
+    /// Internal API - appends data available in the non-blocking stream to the buffer. Returns
+    /// count of the read bytes, zero if there is no data now or the stream is closed.
+    fn read_available<R: Read>(stream: &mut R, out: &mut Vec<u8>) -> std::io::Result<usize> {
+        let mut buf = [0; 32768];
+        match stream.read(&mut buf) {
+            Ok(n) => {
+                out.extend_from_slice(&buf[..n]);
+                Ok(n)
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e)
+        }
+    }
 
-        if sr.is_err() {
-            {
-                let err = self.error.lock().unwrap();
-                if err.is_some() {
-                    return (true, err.as_ref().unwrap().clone())
-                } else {
-                    return (true, String::from("Unknown error"))
+    /// Internal API - opens the sftp subsystem of the session, if it is not opened yet
+    fn open_sftp<'a>(session: &Session, sftp: &'a mut Option<SftpSession>) -> Result<&'a mut SftpSession, String> {
+        if sftp.is_none() {
+            *sftp = Some(SftpSession::new(session)?);
+        }
+
+        Ok(sftp.as_mut().unwrap())
+    }
+
+    /// Internal API - completes the state of the directory transfer and sends its result
+    fn complete_transfer(state_printer: &mut (StatePrinter + Send), r_sender: &Sender<CoData>, result: Result<TransferStats, String>) {
+        match result {
+            Ok(stats) => {
+                state_printer.set_progress(100.0);
+                state_printer.complete_current();
+                r_sender.send(CoData::Transfer(stats));
+            },
+            Err(err_text) => {
+                state_printer.error_current(&err_text);
+                r_sender.send(CoData::Error(err_text));
+            }
+        }
+    }
+
+    /// Internal API - sends the local file through scp. Progress of the sending is shown by the
+    /// state printer. Explicit mode of the existing remote file and the ownership are applied by
+    /// the separate command after the sending. If the skip unchanged or block delta mode is specified,
+    /// the sftp subsystem is used for comparison with the existing remote file and for sending of
+    /// changed blocks.
+    fn transmit_file(session: &Session, sftp: &mut Option<SftpSession>, state_printer: &mut (StatePrinter + Send), source: &str, dest: &str, options: &SendFileOptions) -> Result<SendFileReport, String> {
+        let title = format!("{} -> {}", source, dest);
+
+        let file = File::open(source);
+        if file.is_err() {
+            state_printer.add_one_line("SEND FILE", &title);
+            return Err(format!("Unable to open source file: {}", file.err().unwrap()));
+        }
+        let mut file = file.unwrap();
+
+        let f_meta = file.metadata();
+        if f_meta.is_err() {
+            state_printer.add_one_line("SEND FILE", &title);
+            return Err(format!("Unable to read source file attributes: {}", f_meta.err().unwrap()));
+        }
+        let f_meta = f_meta.unwrap();
+        let f_size = f_meta.len();
+
+        let mode = if options.mode.is_some() {
+            options.mode.unwrap()
+        } else if options.preserve {
+            (f_meta.permissions().mode() & 0o7777) as i32
+        } else {
+            0o644
+        };
+        let times = if options.preserve {
+            Some((f_meta.mtime() as u64, f_meta.atime() as u64))
+        } else {
+            None
+        };
+
+        // Content is sent by scp, unless it is unchanged or it is sent as block delta or resumed through
+        // sftp
+        let mut sftp_used = false;
+        let mut skipped = false;
+        let mut transferred = f_size;
+        let mut resumed = 0;
+        let mut local_hash = None;
+        let mut limiter = RateLimiter::new(options.rate);
+        if options.skip_unchanged || options.block_delta || options.resume {
+            let sftp = Self::open_sftp(session, sftp);
+            if sftp.is_err() {
+                state_printer.add_one_line("SEND FILE", &title);
+                return Err(sftp.err().unwrap());
+            }
+            let sftp = sftp.unwrap().sftp();
+
+            if options.skip_unchanged {
+                let unchanged = is_unchanged(session, sftp, Path::new(source), dest);
+                if unchanged.is_err() {
+                    state_printer.add_one_line("SEND FILE", &title);
+                    return Err(unchanged.err().unwrap());
                 }
+                if unchanged.unwrap() {
+                    state_printer.add_progress("SEND FILE", &title, &format!("{}/{} unchanged", f_size, f_size));
+                    sftp_used = true;
+                    skipped = true;
+                    transferred = 0;
+                }
+            }
+
+            if !skipped && options.resume && sftp.stat(Path::new(dest)).is_ok() {
+                let offset = resume_offset(session, sftp, Path::new(source), dest);
+                if offset.is_err() {
+                    state_printer.add_one_line("SEND FILE", &title);
+                    return Err(offset.err().unwrap());
+                }
+                let offset = offset.unwrap();
+
+                state_printer.add_progress("SEND FILE", &title, &format!("{}/{}", f_size, offset));
+                state_printer.set_transferred(offset, Some(f_size));
+                if f_size > 0 {
+                    state_printer.set_progress(offset as f32 / (f_size as f32 / 100.0));
+                }
+                let mut processed = offset;
+                let sent = send_from(sftp, Path::new(source), dest, offset, &mut limiter, &mut |bytes| {
+                    processed += bytes;
+                    state_printer.set_transferred(processed, Some(f_size));
+                    state_printer.update_bar_title(&format!("{}/{}", f_size, processed));
+                    state_printer.set_progress(processed as f32 / (f_size as f32 / 100.0));
+                })?;
+                state_printer.update_bar_title(&format!("{}/{} resumed from {}", f_size, f_size, offset));
+                sftp_used = true;
+                transferred = sent;
+                resumed = offset;
+            }
+
+            if !skipped && !sftp_used && options.block_delta && sftp.stat(Path::new(dest)).is_ok() {
+                state_printer.add_progress("SEND FILE", &title, &format!("{}/0", f_size));
+                state_printer.set_transferred(0, Some(f_size));
+                let mut processed = 0;
+                let sent = send_delta(session, sftp, Path::new(source), dest, &mut limiter, &mut |bytes| {
+                    processed += bytes;
+                    state_printer.set_transferred(processed, Some(f_size));
+                    state_printer.update_bar_title(&format!("{}/{}", f_size, processed));
+                    state_printer.set_progress(processed as f32 / (f_size as f32 / 100.0));
+                })?;
+                state_printer.update_bar_title(&format!("{}/{} delta {}", f_size, f_size, sent));
+                sftp_used = true;
+                transferred = sent;
             }
         }
 
-        let r = self.r_receiver.recv();
-        if r.is_ok() {
-            match r.unwrap() {
-                CoData::Out(out) => return (false, out),
-                CoData::Error(err) => return (true, err),
-                _ => {
-                    panic!()
+        if !sftp_used {
+            let remote_file = session.scp_send(Path::new(dest), mode, f_size, times);
+            if remote_file.is_err() {
+                state_printer.add_one_line("SEND FILE", &title);
+                return Err(format!("Unable to open dest file: {}", remote_file.err().unwrap()));
+            }
+            let mut remote_file = remote_file.unwrap();
+
+            state_printer.add_progress("SEND FILE", &title, &format!("{}/0", f_size));
+            state_printer.set_transferred(0, Some(f_size));
+
+            let mut buf = [0; BLOCK_SIZE];
+            let mut sent: u64 = 0;
+            let mut hasher = Sha256::new();
+            loop {
+                let read = file.read(&mut buf);
+                if read.is_err() {
+                    return Err(format!("Unable to read source file: {}", read.err().unwrap()));
+                }
+                let read = read.unwrap();
+                if read == 0 {
+                    break;
+                }
+
+                let written = remote_file.write_all(&buf[..read]);
+                if written.is_err() {
+                    return Err(format!("Unable to write dest file: {}", written.err().unwrap()));
+                }
+                if options.verify {
+                    hasher.input(&buf[..read]);
                 }
+
+                sent += read as u64;
+                limiter.consume(read as u64);
+                state_printer.set_transferred(sent, Some(f_size));
+                state_printer.update_bar_title(&format!("{}/{}", f_size, sent));
+                state_printer.set_progress(sent as f32 / (f_size as f32 / 100.0));
             }
-        } else {
-            let err = self.error.lock().unwrap();
-            if err.is_some() {
-                return (true, err.as_ref().unwrap().clone())
-            } else {
-                return (true, String::from("Unknown error"))
+
+            remote_file.send_eof();
+            remote_file.wait_eof();
+            remote_file.close();
+            remote_file.wait_close();
+
+            // Scp declares the size of the file before the content, so the remote file is
+            // incomplete if the local file was truncated during the sending
+            if sent != f_size {
+                return Err(format!("Source file was changed during the sending: {} bytes expected, {} bytes sent", f_size, sent));
             }
+            if options.verify {
+                local_hash = Some(to_hex(&hasher.result()));
+            }
+        } else if options.mode.is_some() || options.preserve {
+            let stat = FileStat {
+                size: None,
+                uid: None,
+                gid: None,
+                perm: Some(mode as u32),
+                atime: times.map(|t| t.1),
+                mtime: times.map(|t| t.0)
+            };
+            let setstat_result = sftp.as_ref().unwrap().sftp().setstat(Path::new(dest), stat);
+            if setstat_result.is_err() {
+                return Err(format!("Unable to set attributes of dest file: {}", setstat_result.err().unwrap()));
+            }
+        }
+
+        // Content sent through sftp is hashed after the sending, unchanged file was already
+        // compared by hash
+        if options.verify && !skipped {
+            if local_hash.is_none() {
+                local_hash = Some(local_sha256(Path::new(source))?);
+            }
+            let remote_hash = remote_sha256(session, dest)?;
+            if local_hash.as_ref() != Some(&remote_hash) {
+                return Err(format!("Verification of the sent file failed: local sha256 {}, remote sha256 {}", local_hash.unwrap(), remote_hash));
+            }
+            state_printer.update_bar_title(&format!("{}/{} verified", f_size, f_size));
+        }
+
+        // Scp does not change mode of the existing file without times preserving
+        let mut commands = Vec::new();
+        if options.mode.is_some() && times.is_none() && !sftp_used {
+            commands.push(format!("chmod {:o} {}", mode, shell_quote(dest)));
+        }
+        if options.owner.is_some() {
+            commands.push(format!("chown {} {}", shell_quote(options.owner.as_ref().unwrap()), shell_quote(dest)));
+        }
+        if options.group.is_some() {
+            commands.push(format!("chgrp {} {}", shell_quote(options.group.as_ref().unwrap()), shell_quote(dest)));
+        }
+        if !commands.is_empty() {
+            let result = Self::run_command(session, &commands.join(" && "))?;
+            if !result.is_success() {
+                return Err(format!("Unable to set attributes of dest file: {}", result.stderr.trim()));
+            }
+        }
+
+        Ok(SendFileReport {
+            mode,
+            times,
+            owner: options.owner.clone(),
+            group: options.group.clone(),
+            skipped,
+            transferred,
+            resumed,
+            sha256: local_hash
+        })
+    }
+
+    /// Internal API - receives the remote file through scp. Missing local directories are created,
+    /// mode of the remote file is set to the local file. If the local path is a directory, the
+    /// file is saved into it with the remote name. Progress of the receiving is shown by the state
+    /// printer.
+    fn receive_file(session: &Session, state_printer: &mut (StatePrinter + Send), source: &str, dest: &str, rate: Option<u64>) -> Result<(), String> {
+        let title = format!("{} -> {}", source, dest);
+
+        let remote = session.scp_recv(Path::new(source));
+        if remote.is_err() {
+            state_printer.add_one_line("FETCH FILE", &title);
+            return Err(format!("Unable to open source file: {}", remote.err().unwrap()));
+        }
+        let (mut remote_file, stat) = remote.unwrap();
+        let f_size = stat.size();
+
+        let mut dest_path = PathBuf::from(dest);
+        if dest.ends_with('/') || dest_path.is_dir() {
+            let name = Path::new(source).file_name();
+            if name.is_none() {
+                state_printer.add_one_line("FETCH FILE", &title);
+                return Err(format!("Unable to determine file name of '{}'", source));
+            }
+            dest_path.push(name.unwrap());
+        }
+
+        if let Some(parent) = dest_path.parent() {
+            if !parent.as_os_str().is_empty() {
+                let mkdir_result = fs::create_dir_all(parent);
+                if mkdir_result.is_err() {
+                    state_printer.add_one_line("FETCH FILE", &title);
+                    return Err(format!("Unable to create dest directory: {}", mkdir_result.err().unwrap()));
+                }
+            }
+        }
+
+        let file = File::create(&dest_path);
+        if file.is_err() {
+            state_printer.add_one_line("FETCH FILE", &title);
+            return Err(format!("Unable to open dest file: {}", file.err().unwrap()));
+        }
+        let mut file = file.unwrap();
+
+        state_printer.add_progress("FETCH FILE", &title, &format!("{}/0", f_size));
+        state_printer.set_transferred(0, Some(f_size));
+
+        let mut buf = [0; BLOCK_SIZE];
+        let mut received: u64 = 0;
+        let mut limiter = RateLimiter::new(rate);
+        while received < f_size {
+            let to_read = std::cmp::min(BLOCK_SIZE as u64, f_size - received) as usize;
+            let read = remote_file.read(&mut buf[..to_read]);
+            if read.is_err() {
+                return Err(format!("Unable to read source file: {}", read.err().unwrap()));
+            }
+            let read = read.unwrap();
+            if read == 0 {
+                return Err(String::from("Unable to read source file: unexpected end of file"));
+            }
+
+            let written = file.write_all(&buf[..read]);
+            if written.is_err() {
+                return Err(format!("Unable to write dest file: {}", written.err().unwrap()));
+            }
+
+            received += read as u64;
+            limiter.consume(read as u64);
+            state_printer.set_transferred(received, Some(f_size));
+            state_printer.update_bar_title(&format!("{}/{}", f_size, received));
+            state_printer.set_progress(received as f32 / (f_size as f32 / 100.0));
+        }
+
+        remote_file.send_eof();
+        remote_file.wait_eof();
+        remote_file.close();
+        remote_file.wait_close();
+
+        let chmod_result = fs::set_permissions(&dest_path, fs::Permissions::from_mode(stat.mode() as u32 & 0o7777));
+        if chmod_result.is_err() {
+            return Err(format!("Unable to set mode of dest file: {}", chmod_result.err().unwrap()));
         }
 
+        Ok(())
     }
 
-    /// Sends file to the remote server. Returns error flag and error text of error if it was o
-    /// occurs
-    pub fn send_file(&mut self, source: String, dest: String) -> (bool, String) {
-        let sr = self.sender.send(CoOps::SendFile(source, dest));
+    /// Internal API - opens direct-tcpip channel to the host through the session and creates tunnel
+    /// between it and the local stream pair. Returns the tunnel and the free end of the pair.
+    fn create_tunnel(session: &Session, host: &str, port: u16) -> Result<(Tunnel, UnixStream), String> {
+        let channel = session.channel_direct_tcpip(host, port, None);
+        if channel.is_err() {
+            return Err(format!("Unable to open tunnel to {}:{}: {}", host, port, channel.err().unwrap()));
+        }
+
+        let pair = UnixStream::pair();
+        if pair.is_err() {
+            return Err(format!("Unable to create local tunnel socket: {}", pair.err().unwrap()));
+        }
+        let (local, remote) = pair.unwrap();
+        let nonblocking_result = local.set_nonblocking(true);
+        if nonblocking_result.is_err() {
+            return Err(format!("Unable to create local tunnel socket: {}", nonblocking_result.err().unwrap()));
+        }
+
+        Ok((Tunnel::new(channel.unwrap(), Box::new(local)), remote))
+    }
+
+    /// Internal API - passes available data through all tunnels and drops closed of them. Returns
+    /// true if some data was passed.
+    fn pump_tunnels(session: &Session, tunnels: &mut Vec<Tunnel>) -> bool {
+        let mut moved = 0;
+
+        session.set_blocking(false);
+        tunnels.retain_mut(|tunnel| {
+            match tunnel.pump() {
+                Some(n) => {
+                    moved += n;
+                    true
+                },
+                None => false
+            }
+        });
+        session.set_blocking(true);
+
+        moved > 0
+    }
+
+    /// Internal API - returns text of the connection error or a stub if it is not set
+    fn last_error(&self) -> String {
+        let err = self.error.lock().unwrap();
+        if err.is_some() {
+            err.as_ref().unwrap().clone()
+        } else {
+            String::from("Unknown error")
+        }
+    }
+
+    /// Execute shell command on the remote server with the specified options. Returns the result
+    /// of the executed command, or text of the error if the command was not executed.
+    pub fn exec(&mut self, cmd: String, prompt: Option<String>, with_prompt: Option<bool>, options: ExecOptions) -> Result<ExecResult, String> {
+        let sr = self.sender.send(CoOps::Exec1(cmd, prompt, with_prompt, options));
 
         if sr.is_err() {
             {
                 let err = self.error.lock().unwrap();
                 if err.is_some() {
-                    return (true, err.as_ref().unwrap().clone())
+                    return Err(err.as_ref().unwrap().clone())
                 } else {
-                    return (true, String::from("Unknown error"))
+                    return Err(String::from("Unknown error"))
                 }
             }
         }
@@ -434,8 +1532,8 @@ impl Connection {
         let r = self.r_receiver.recv();
         if r.is_ok() {
             match r.unwrap() {
-                CoData::BoolResult(r) => return (true, String::new()),
-                CoData::Error(err) => return (true, err),
+                CoData::Exec(result) => return Ok(result),
+                CoData::Error(err) => return Err(err),
                 _ => {
                     panic!()
                 }
@@ -443,11 +1541,167 @@ impl Connection {
         } else {
             let err = self.error.lock().unwrap();
             if err.is_some() {
-                return (true, err.as_ref().unwrap().clone())
+                return Err(err.as_ref().unwrap().clone())
             } else {
-                return (true, String::from("Unknown error"))
+                return Err(String::from("Unknown error"))
             }
         }
+
+    }
+
+    /// Execute command on the remote server through a dedicated exec channel without pty. Unlike
+    /// exec, this method does not interact with the shell, so it returns real exit code of the
+    /// command and separate stdout and stderr outputs. Returns text of error if it was occurs.
+    pub fn run(&mut self, cmd: String) -> Result<RunResult, String> {
+        if self.sender.send(CoOps::Run(cmd)).is_err() {
+            return Err(self.last_error());
+        }
+
+        match self.r_receiver.recv() {
+            Ok(CoData::RunResult(result)) => Ok(result),
+            Ok(CoData::Error(err)) => Err(err),
+            Ok(_) => panic!(),
+            Err(_) => Err(self.last_error())
+        }
+    }
+
+    /// Opens tunnel to the host through this connection. Returns the local end of the tunnel, which
+    /// may be used as transport of another ssh session.
+    pub fn open_tunnel(&mut self, host: String, port: u16) -> Result<UnixStream, String> {
+        if self.sender.send(CoOps::OpenTunnel(host, port)).is_err() {
+            return Err(self.last_error());
+        }
+
+        match self.r_receiver.recv() {
+            Ok(CoData::Tunnel(stream)) => Ok(stream),
+            Ok(CoData::Error(err)) => Err(err),
+            Ok(_) => panic!(),
+            Err(_) => Err(self.last_error())
+        }
+    }
+
+    /// Receives the file from the remote fs to the local fs. Rate limits the transfer in bytes per
+    /// second, if not specified, the default limit of the connection is used. Returns error flag and
+    /// text of the error if it was occurs
+    pub fn fetch_file(&mut self, source: String, dest: String, rate: Option<u64>) -> (bool, String) {
+        if self.sender.send(CoOps::FetchFile(source, dest, rate)).is_err() {
+            return (true, self.last_error());
+        }
+
+        match self.r_receiver.recv() {
+            Ok(CoData::BoolResult(_)) => (false, String::new()),
+            Ok(CoData::Error(err)) => (true, err),
+            Ok(_) => panic!(),
+            Err(_) => (true, self.last_error())
+        }
+    }
+
+    /// Sends the local directory to the remote fs recursively with the specified options. Returns
+    /// statistics of the transfer.
+    pub fn send_dir(&mut self, source: String, dest: String, options: DirTransferOptions) -> Result<TransferStats, String> {
+        self.transfer_dir(CoOps::SendDir(source, dest, options))
+    }
+
+    /// Receives the remote directory to the local fs recursively with the specified options.
+    /// Returns statistics of the transfer.
+    pub fn fetch_dir(&mut self, source: String, dest: String, options: DirTransferOptions) -> Result<TransferStats, String> {
+        self.transfer_dir(CoOps::FetchDir(source, dest, options))
+    }
+
+    /// Internal API - executes the directory transfer operation
+    fn transfer_dir(&mut self, op: CoOps) -> Result<TransferStats, String> {
+        if self.sender.send(op).is_err() {
+            return Err(self.last_error());
+        }
+
+        match self.r_receiver.recv() {
+            Ok(CoData::Transfer(stats)) => Ok(stats),
+            Ok(CoData::Error(err)) => Err(err),
+            Ok(_) => panic!(),
+            Err(_) => Err(self.last_error())
+        }
+    }
+
+    /// Starts forwarding of the local port to the port of the host, which is accessed from the
+    /// remote server. If local port is 0, any free port is used. Returns identifier of the
+    /// forwarding and the bound local port. Forwarding works until it is closed with
+    /// close_forward or until the connection is closed.
+    pub fn forward_local(&mut self, local_port: u16, host: String, port: u16) -> Result<(usize, u16), String> {
+        if self.sender.send(CoOps::ForwardLocal(local_port, host, port)).is_err() {
+            return Err(self.last_error());
+        }
+
+        match self.r_receiver.recv() {
+            Ok(CoData::Forward(id, bound_port)) => Ok((id, bound_port)),
+            Ok(CoData::Error(err)) => Err(err),
+            Ok(_) => panic!(),
+            Err(_) => Err(self.last_error())
+        }
+    }
+
+    /// Starts forwarding of the remote port, which is listened by the remote server, to the port of
+    /// the host, which is accessed from the local machine. If remote port is 0, the server chooses
+    /// any free port. Returns identifier of the forwarding and the bound remote port. Forwarding
+    /// works until it is closed with close_forward or until the connection is closed.
+    pub fn forward_remote(&mut self, remote_port: u16, host: String, port: u16) -> Result<(usize, u16), String> {
+        if self.sender.send(CoOps::ForwardRemote(remote_port, host, port)).is_err() {
+            return Err(self.last_error());
+        }
+
+        match self.r_receiver.recv() {
+            Ok(CoData::Forward(id, bound_port)) => Ok((id, bound_port)),
+            Ok(CoData::Error(err)) => Err(err),
+            Ok(_) => panic!(),
+            Err(_) => Err(self.last_error())
+        }
+    }
+
+    /// Returns identifiers and descriptions of the active port forwardings
+    pub fn forwards(&mut self) -> Vec<(usize, String)> {
+        if self.sender.send(CoOps::ListForwards).is_err() {
+            return Vec::new();
+        }
+
+        match self.r_receiver.recv() {
+            Ok(CoData::Forwards(list)) => list,
+            _ => Vec::new()
+        }
+    }
+
+    /// Executes the operation through the sftp subsystem of the connection. Subsystem is opened at
+    /// the first operation.
+    pub fn sftp(&mut self, op: SftpOp) -> Result<SftpValue, SftpError> {
+        if self.sender.send(CoOps::Sftp(op)).is_err() {
+            return Err(SftpError { code: None, text: self.last_error() });
+        }
+
+        match self.r_receiver.recv() {
+            Ok(CoData::Sftp(result)) => result,
+            Ok(_) => panic!(),
+            Err(_) => Err(SftpError { code: None, text: self.last_error() })
+        }
+    }
+
+    /// Stops the port forwarding and closes all its tunnels
+    pub fn close_forward(&mut self, id: usize) {
+        if self.sender.send(CoOps::CloseForward(id)).is_ok() {
+            self.r_receiver.recv();
+        }
+    }
+
+    /// Sends file to the remote server with the specified options. Returns attributes which was
+    /// applied to the remote file, or text of the error
+    pub fn send_file(&mut self, source: String, dest: String, options: SendFileOptions) -> Result<SendFileReport, String> {
+        if self.sender.send(CoOps::SendFile(source, dest, options)).is_err() {
+            return Err(self.last_error());
+        }
+
+        match self.r_receiver.recv() {
+            Ok(CoData::SendReport(report)) => Ok(report),
+            Ok(CoData::Error(err)) => Err(err),
+            Ok(_) => panic!(),
+            Err(_) => Err(self.last_error())
+        }
     }
 
     /// Setup new prompt for connection. Pattern must be a valid rust regexp. If pattern is
@@ -468,8 +1722,49 @@ impl Connection {
         self.error.lock().unwrap().clone()
     }
 
-    /// Closes connection
+    /// Closes connection. Waits until the connection thread is finished, so all resources of the
+    /// connection (like the proxy process) are released on return.
     pub fn close(&mut self) {
         self.sender.send(CoOps::Close);
+        while self.r_receiver.recv().is_ok() {}
+    }
+}
+
+/// Quotes the string for the posix shell
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_addr_host_only() {
+        assert_eq!(Connection::split_addr("db01.internal").unwrap(), (String::from("db01.internal"), None));
+        assert_eq!(Connection::split_addr("  10.0.0.5 ").unwrap(), (String::from("10.0.0.5"), None));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn split_addr_host_with_port() {
+        assert_eq!(Connection::split_addr("db01.internal:2222").unwrap(), (String::from("db01.internal"), Some(2222)));
+    }
+
+    #[test]
+    fn split_addr_ipv6() {
+        assert_eq!(Connection::split_addr("[::1]").unwrap(), (String::from("::1"), None));
+        assert_eq!(Connection::split_addr("[::1]:22").unwrap(), (String::from("::1"), Some(22)));
+        assert_eq!(Connection::split_addr("fe80::1").unwrap(), (String::from("fe80::1"), None));
+    }
+
+    #[test]
+    fn split_addr_invalid() {
+        assert!(Connection::split_addr("[::1").is_err());
+        assert!(Connection::split_addr("[::1]22").is_err());
+        assert!(Connection::split_addr("[]:22").is_err());
+        assert!(Connection::split_addr(":22").is_err());
+        assert!(Connection::split_addr("host:").is_err());
+        assert!(Connection::split_addr("host:70000").is_err());
+        assert!(Connection::split_addr("host:ssh").is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Comparison of the local and remote files and transfer of the changed blocks only. Blocks are
+//! compared at fixed offsets, there is no rolling checksum as in rsync. Hashes of the remote files
+//! are computed on the server by sha256sum (or shasum on systems without it), so no additional
+//! software is required on the remote side.
+
+use super::connection::{Connection, shell_quote};
+use super::rate_limit::RateLimiter;
+use crate::runtime::script_runtime::BLOCK_SIZE;
+use ssh2::{Session, Sftp, FileStat, OpenFlags, OpenType};
+use sha2::{Sha256, Digest};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+
+/// Size of the block which is compared in the delta mode
+pub const DELTA_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Returns sha256 hex digest of the local file
+pub fn local_sha256(path: &Path) -> Result<String, String> {
+    let file = File::open(path);
+    if file.is_err() {
+        return Err(format!("Unable to open '{}': {}", path.display(), file.err().unwrap()));
+    }
+    let mut file = file.unwrap();
+
+    let mut hasher = Sha256::new();
+    let mut buf = [0; BLOCK_SIZE];
+    loop {
+        let read = file.read(&mut buf);
+        if read.is_err() {
+            return Err(format!("Unable to read '{}': {}", path.display(), read.err().unwrap()));
+        }
+        let read = read.unwrap();
+        if read == 0 {
+            break;
+        }
+        hasher.input(&buf[..read]);
+    }
+
+    Ok(to_hex(&hasher.result()))
+}
+
+/// Returns sha256 hex digest of the remote file
+pub fn remote_sha256(session: &Session, path: &str) -> Result<String, String> {
+    let path = shell_quote(path);
+    let cmd = format!("sha256sum {} 2>/dev/null || shasum -a 256 {}", path, path);
+    let result = Connection::run_command(session, &cmd)?;
+    if !result.is_success() {
+        return Err(format!("Unable to compute hash of remote file: {}", result.stderr.trim()));
+    }
+
+    parse_hash(&result.stdout)
+        .ok_or(format!("Unexpected output of remote hash command: {}", result.stdout.trim()))
+}
+
+/// Checks that the remote file has the same size and content as the local file. Returns false if
+/// the remote file does not exist.
+pub fn is_unchanged(session: &Session, sftp: &Sftp, local: &Path, remote: &str) -> Result<bool, String> {
+    let local_size = local.metadata().map(|m| m.len());
+    if local_size.is_err() {
+        return Err(format!("Unable to read attributes of '{}': {}", local.display(), local_size.err().unwrap()));
+    }
+
+    let remote_size = sftp.stat(Path::new(remote)).ok().and_then(|s| s.size);
+    if remote_size != Some(local_size.unwrap()) {
+        return Ok(false);
+    }
+
+    Ok(local_sha256(local)? == remote_sha256(session, remote)?)
+}
+
+/// Writes the local file to the existing remote file through sftp, sending only blocks which differ
+/// from the blocks of the remote file at the same offsets. Remote file is truncated to the size of
+/// the local file. Progress is called with the count of the processed bytes, only the sent blocks
+/// are accounted by the limiter. Returns count of the sent bytes.
+pub fn send_delta(session: &Session, sftp: &Sftp, local: &Path, remote: &str, limiter: &mut RateLimiter, progress: &mut FnMut(u64)) -> Result<u64, String> {
+    let remote_size = sftp.stat(Path::new(remote)).ok().and_then(|s| s.size).unwrap_or(0);
+    let remote_hashes = remote_block_hashes(session, remote, remote_size)?;
+
+    let file = File::open(local);
+    if file.is_err() {
+        return Err(format!("Unable to open source file '{}': {}", local.display(), file.err().unwrap()));
+    }
+    let mut file = file.unwrap();
+    let local_size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+    let remote_file = sftp.open_mode(Path::new(remote), OpenFlags::WRITE | OpenFlags::CREATE, 0o644, OpenType::File);
+    if remote_file.is_err() {
+        return Err(format!("Unable to open dest file '{}': {}", remote, remote_file.err().unwrap()));
+    }
+    let mut remote_file = remote_file.unwrap();
+
+    let mut buf = vec![0; DELTA_BLOCK_SIZE as usize];
+    let mut sent = 0;
+    let mut index = 0;
+    loop {
+        let read = read_block(&mut file, &mut buf);
+        if read.is_err() {
+            return Err(format!("Unable to read source file '{}': {}", local.display(), read.err().unwrap()));
+        }
+        let read = read.unwrap();
+        if read == 0 {
+            break;
+        }
+
+        let hash = to_hex(&Sha256::digest(&buf[..read]));
+        if remote_hashes.get(index) != Some(&hash) {
+            let offset = index as u64 * DELTA_BLOCK_SIZE;
+            let write_result = remote_file.seek(SeekFrom::Start(offset))
+                .and_then(|_| remote_file.write_all(&buf[..read]));
+            if write_result.is_err() {
+                return Err(format!("Unable to write dest file '{}': {}", remote, write_result.err().unwrap()));
+            }
+            sent += read as u64;
+            limiter.consume(read as u64);
+        }
+
+        progress(read as u64);
+        index += 1;
+    }
+
+    if remote_size > local_size {
+        let stat = FileStat {
+            size: Some(local_size),
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: None
+        };
+        let truncate_result = remote_file.setstat(stat);
+        if truncate_result.is_err() {
+            return Err(format!("Unable to truncate dest file '{}': {}", remote, truncate_result.err().unwrap()));
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Returns offset from which the partially sent remote file can be continued. Blocks of the remote
+/// file are compared with the blocks of the local file from the start, the offset is the end of the
+/// last matched block. The last incomplete block of the remote file is compared with the same count
+/// of bytes of the local file. Returns 0 if the remote file does not exist.
+pub fn resume_offset(session: &Session, sftp: &Sftp, local: &Path, remote: &str) -> Result<u64, String> {
+    let remote_size = sftp.stat(Path::new(remote)).ok().and_then(|s| s.size);
+    if remote_size.is_none() {
+        return Ok(0);
+    }
+    let remote_hashes = remote_block_hashes(session, remote, remote_size.unwrap())?;
+
+    let file = File::open(local);
+    if file.is_err() {
+        return Err(format!("Unable to open source file '{}': {}", local.display(), file.err().unwrap()));
+    }
+    let mut file = file.unwrap();
+
+    let mut buf = vec![0; DELTA_BLOCK_SIZE as usize];
+    let mut offset = 0;
+    for (index, remote_hash) in remote_hashes.iter().enumerate() {
+        let block_size = std::cmp::min(DELTA_BLOCK_SIZE, remote_size.unwrap() - index as u64 * DELTA_BLOCK_SIZE) as usize;
+        let read = read_block(&mut file, &mut buf[..block_size]);
+        if read.is_err() {
+            return Err(format!("Unable to read source file '{}': {}", local.display(), read.err().unwrap()));
+        }
+        let read = read.unwrap();
+        if read < block_size || to_hex(&Sha256::digest(&buf[..read])) != *remote_hash {
+            break;
+        }
+        offset += read as u64;
+    }
+
+    Ok(offset)
+}
+
+/// Writes the local file to the remote file through sftp starting from the offset, the preceding
+/// part of the remote file is kept. Remote file is truncated to the size of the local file.
+/// Progress is called with the count of the written bytes. Returns count of the sent bytes.
+pub fn send_from(sftp: &Sftp, local: &Path, remote: &str, offset: u64, limiter: &mut RateLimiter, progress: &mut FnMut(u64)) -> Result<u64, String> {
+    let file = File::open(local);
+    if file.is_err() {
+        return Err(format!("Unable to open source file '{}': {}", local.display(), file.err().unwrap()));
+    }
+    let mut file = file.unwrap();
+    let local_size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+    let seek_result = file.seek(SeekFrom::Start(offset));
+    if seek_result.is_err() {
+        return Err(format!("Unable to read source file '{}': {}", local.display(), seek_result.err().unwrap()));
+    }
+
+    let remote_file = sftp.open_mode(Path::new(remote), OpenFlags::WRITE | OpenFlags::CREATE, 0o644, OpenType::File);
+    if remote_file.is_err() {
+        return Err(format!("Unable to open dest file '{}': {}", remote, remote_file.err().unwrap()));
+    }
+    let mut remote_file = remote_file.unwrap();
+
+    let seek_result = remote_file.seek(SeekFrom::Start(offset));
+    if seek_result.is_err() {
+        return Err(format!("Unable to write dest file '{}': {}", remote, seek_result.err().unwrap()));
+    }
+
+    let mut buf = [0; BLOCK_SIZE];
+    let mut sent = 0;
+    loop {
+        let read = file.read(&mut buf);
+        if read.is_err() {
+            return Err(format!("Unable to read source file '{}': {}", local.display(), read.err().unwrap()));
+        }
+        let read = read.unwrap();
+        if read == 0 {
+            break;
+        }
+
+        let write_result = remote_file.write_all(&buf[..read]);
+        if write_result.is_err() {
+            return Err(format!("Unable to write dest file '{}': {}", remote, write_result.err().unwrap()));
+        }
+        sent += read as u64;
+        limiter.consume(read as u64);
+        progress(read as u64);
+    }
+
+    let stat = FileStat {
+        size: Some(local_size),
+        uid: None,
+        gid: None,
+        perm: None,
+        atime: None,
+        mtime: None
+    };
+    let truncate_result = remote_file.setstat(stat);
+    if truncate_result.is_err() {
+        return Err(format!("Unable to truncate dest file '{}': {}", remote, truncate_result.err().unwrap()));
+    }
+
+    Ok(sent)
+}
+
+/// Returns sha256 hex digests of the blocks of the remote file. The file is read in a single pass:
+/// by perl in one process if it is available, otherwise by GNU split or by dd invocations which
+/// read the blocks in turn from the same stdin, so the command works with any posix shell.
+pub fn remote_block_hashes(session: &Session, path: &str, size: u64) -> Result<Vec<String>, String> {
+    let blocks = (size + DELTA_BLOCK_SIZE - 1) / DELTA_BLOCK_SIZE;
+    if blocks == 0 {
+        return Ok(Vec::new());
+    }
+
+    let cmd = format!("f={path}; \
+                       perl -MDigest::SHA=sha256_hex -e 'binmode STDIN; while (read(STDIN, $b, {size})) {{ print sha256_hex($b), \"\\n\" }}' < \"$f\" 2>/dev/null || \
+                       split -b {size} --filter='sha256sum 2>/dev/null || shasum -a 256' \"$f\" 2>/dev/null || \
+                       {{ i=0; while [ $i -lt {blocks} ]; do dd bs={size} count=1 2>/dev/null | (sha256sum 2>/dev/null || shasum -a 256) || exit 1; i=$((i+1)); done; }} < \"$f\"",
+                      path = shell_quote(path), size = DELTA_BLOCK_SIZE, blocks = blocks);
+    let result = Connection::run_command(session, &cmd)?;
+    if !result.is_success() {
+        return Err(format!("Unable to compute block hashes of remote file: {}", result.stderr.trim()));
+    }
+
+    let hashes: Vec<String> = result.stdout.lines().filter_map(parse_hash).collect();
+    if hashes.len() as u64 != blocks {
+        return Err(String::from("Unable to compute block hashes of remote file: unexpected output of the hash command"));
+    }
+
+    Ok(hashes)
+}
+
+/// Reads the full block from the file, or less at the end of the file
+fn read_block(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        let n = file.read(&mut buf[read..])?;
+        if n == 0 {
+            break;
+        }
+        read += n;
+    }
+
+    Ok(read)
+}
+
+/// Extracts the hex digest from the output line of sha256sum
+fn parse_hash(line: &str) -> Option<String> {
+    let hash = line.split_whitespace().next()?;
+    if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(hash.to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Encodes bytes as lowercase hex string
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn to_hex_lowercase_with_leading_zeros() {
+        assert_eq!(to_hex(&[0x00, 0x0f, 0xab, 0xff]), "000fabff");
+        assert_eq!(to_hex(&[]), "");
+        assert_eq!(to_hex(&Sha256::digest(b"abc")), ABC_SHA256);
+    }
+
+    #[test]
+    fn parse_hash_outputs_of_hash_commands() {
+        // sha256sum and shasum of the stdin, sha256sum of the file and perl
+        assert_eq!(parse_hash(&format!("{}  -", ABC_SHA256)), Some(String::from(ABC_SHA256)));
+        assert_eq!(parse_hash(&format!("{} *file name", ABC_SHA256)), Some(String::from(ABC_SHA256)));
+        assert_eq!(parse_hash(ABC_SHA256), Some(String::from(ABC_SHA256)));
+    }
+
+    #[test]
+    fn parse_hash_uppercase() {
+        assert_eq!(parse_hash(&ABC_SHA256.to_uppercase()), Some(String::from(ABC_SHA256)));
+    }
+
+    #[test]
+    fn parse_hash_invalid() {
+        assert_eq!(parse_hash(""), None);
+        assert_eq!(parse_hash(&ABC_SHA256[1..]), None);
+        assert_eq!(parse_hash(&format!("{}0", ABC_SHA256)), None);
+        assert_eq!(parse_hash(&ABC_SHA256.replace('a', "g")), None);
+        assert_eq!(parse_hash("sha256sum: file: No such file or directory"), None);
+    }
+
+    #[test]
+    fn local_sha256_of_file() {
+        let path = std::env::temp_dir().join(format!("trs-delta-test-{}", std::process::id()));
+        fs::write(&path, b"abc").unwrap();
+        let hash = local_sha256(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(hash, Ok(String::from(ABC_SHA256)));
+    }
+}
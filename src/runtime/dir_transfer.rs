@@ -0,0 +1,610 @@
+//! Recursive transfer of directories through the sftp subsystem. Directory trees are recreated
+//! with modes of the source entries, symlinks are copied as symlinks.
+
+use crate::state_printer::state_printer::StatePrinter;
+use crate::runtime::script_runtime::BLOCK_SIZE;
+use super::delta::{is_unchanged, send_delta};
+use super::rate_limit::RateLimiter;
+use ssh2::{Session, Sftp, FileStat, FileType, OpenFlags, OpenType};
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+
+/// Options of the directory transfer
+#[derive(Default)]
+pub struct DirTransferOptions {
+
+    /// Patterns of the skipped entries
+    pub exclude: Vec<String>,
+
+    /// Do not transfer files which have the same size and sha256 hash on both sides
+    pub skip_unchanged: bool,
+
+    /// Send only changed blocks of the existing remote files, see SendFileOptions. Used only for
+    /// sending.
+    pub block_delta: bool,
+
+    /// Limit of the transfer rate in bytes per second
+    pub rate: Option<u64>
+}
+
+/// Statistics of the completed transfer
+pub struct TransferStats {
+
+    /// Count of the transferred regular files
+    pub files: u64,
+
+    /// Count of the created directories
+    pub dirs: u64,
+
+    /// Count of the created symlinks
+    pub symlinks: u64,
+
+    /// Count of the unchanged files, which was not transferred
+    pub skipped: u64,
+
+    /// Count of the transferred bytes
+    pub bytes: u64
+}
+
+/// Type of the entry of the transferred tree
+enum EntryKind {
+    Dir,
+    File(u64),
+    Symlink(String)
+}
+
+/// Entry of the transferred tree
+struct Entry {
+
+    /// Path relative to the root of the tree, in form 'a/b/c'
+    path: String,
+
+    /// Type of the entry
+    kind: EntryKind,
+
+    /// Permission bits of the entry
+    mode: u32
+}
+
+/// Progress of the transfer, which is shown by the state printer
+struct Progress<'a> {
+    state_printer: &'a mut (StatePrinter + Send),
+    files_total: u64,
+    bytes_total: u64,
+    files: u64,
+    bytes: u64
+}
+
+impl <'a> Progress<'a> {
+
+    /// Adds the transferred bytes and updates the progress bar
+    fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.update();
+    }
+
+    /// Adds the transferred file and updates the progress bar
+    fn add_file(&mut self) {
+        self.files += 1;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        self.state_printer.set_transferred(self.bytes, Some(self.bytes_total));
+        self.state_printer.update_bar_title(&format!("files {}/{}, bytes {}/{}", self.files, self.files_total, self.bytes, self.bytes_total));
+        let percent = if self.bytes_total > 0 {
+            self.bytes as f32 / (self.bytes_total as f32 / 100.0)
+        } else {
+            self.files as f32 / (self.files_total as f32 / 100.0)
+        };
+        self.state_printer.set_progress(percent);
+    }
+}
+
+/// Sends the local directory to the remote directory. Entries which match any of the exclude
+/// patterns are skipped. Progress of the transfer is shown by the state printer.
+pub fn send_dir(session: &Session, sftp: &Sftp, state_printer: &mut (StatePrinter + Send), source: &str, dest: &str, options: &DirTransferOptions) -> Result<TransferStats, String> {
+    let exclude = &options.exclude;
+    let title = format!("{} -> {}", source, dest);
+
+    let root_meta = fs::metadata(source);
+    if root_meta.is_err() || !root_meta.as_ref().unwrap().is_dir() {
+        state_printer.add_one_line("SEND DIR", &title);
+        return Err(format!("Source '{}' is not a directory", source));
+    }
+    let root_mode = root_meta.unwrap().permissions().mode() & 0o7777;
+
+    let mut entries = Vec::new();
+    let scan_result = scan_local(Path::new(source), "", exclude, &mut entries);
+    if scan_result.is_err() {
+        state_printer.add_one_line("SEND DIR", &title);
+        return Err(scan_result.err().unwrap());
+    }
+
+    let mut progress = start_progress(state_printer, "SEND DIR", &title, &entries);
+    let mut stats = TransferStats { files: 0, dirs: 0, symlinks: 0, skipped: 0, bytes: 0 };
+    let mut limiter = RateLimiter::new(options.rate);
+
+    // Directories are created writable, them final modes are set after the transfer of content
+    let mut dirs = vec![(String::from(dest), root_mode)];
+    remote_mkdir(sftp, dest)?;
+    for entry in entries {
+        let local_path = Path::new(source).join(&entry.path);
+        let remote_path = format!("{}/{}", dest.trim_end_matches('/'), entry.path);
+
+        match entry.kind {
+            EntryKind::Dir => {
+                remote_mkdir(sftp, &remote_path)?;
+                dirs.push((remote_path, entry.mode));
+                stats.dirs += 1;
+            },
+            EntryKind::Symlink(target) => {
+                if sftp.lstat(Path::new(&remote_path)).is_ok() {
+                    let unlink_result = sftp.unlink(Path::new(&remote_path));
+                    if unlink_result.is_err() {
+                        return Err(format!("Unable to remove '{}': {}", remote_path, unlink_result.err().unwrap()));
+                    }
+                }
+                // OpenSSH server swaps arguments of the symlink request, so the link is created
+                // at the second path
+                let symlink_result = sftp.symlink(Path::new(&target), Path::new(&remote_path));
+                if symlink_result.is_err() {
+                    return Err(format!("Unable to create symlink '{}': {}", remote_path, symlink_result.err().unwrap()));
+                }
+                stats.symlinks += 1;
+            },
+            EntryKind::File(size) => {
+                if options.skip_unchanged && is_unchanged(session, sftp, &local_path, &remote_path)? {
+                    remote_chmod(sftp, &remote_path, entry.mode)?;
+                    stats.skipped += 1;
+                    progress.add_bytes(size);
+                    progress.add_file();
+                    continue;
+                }
+
+                if options.block_delta && sftp.stat(Path::new(&remote_path)).is_ok() {
+                    let sent = send_delta(session, sftp, &local_path, &remote_path, &mut limiter, &mut |bytes| progress.add_bytes(bytes))?;
+                    remote_chmod(sftp, &remote_path, entry.mode)?;
+                    stats.bytes += sent;
+                    stats.files += 1;
+                    progress.add_file();
+                    continue;
+                }
+
+                let file = File::open(&local_path);
+                if file.is_err() {
+                    return Err(format!("Unable to open source file '{}': {}", local_path.display(), file.err().unwrap()));
+                }
+                let mut file = file.unwrap();
+
+                let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+                let remote_file = sftp.open_mode(Path::new(&remote_path), flags, entry.mode as i32, OpenType::File);
+                if remote_file.is_err() {
+                    return Err(format!("Unable to open dest file '{}': {}", remote_path, remote_file.err().unwrap()));
+                }
+                let mut remote_file = remote_file.unwrap();
+
+                let mut buf = [0; BLOCK_SIZE];
+                loop {
+                    let read = file.read(&mut buf);
+                    if read.is_err() {
+                        return Err(format!("Unable to read source file '{}': {}", local_path.display(), read.err().unwrap()));
+                    }
+                    let read = read.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    let written = remote_file.write_all(&buf[..read]);
+                    if written.is_err() {
+                        return Err(format!("Unable to write dest file '{}': {}", remote_path, written.err().unwrap()));
+                    }
+                    stats.bytes += read as u64;
+                    limiter.consume(read as u64);
+                    progress.add_bytes(read as u64);
+                }
+
+                // Mode of the new file is affected by the umask of the server
+                remote_chmod(sftp, &remote_path, entry.mode)?;
+                stats.files += 1;
+                progress.add_file();
+            }
+        }
+    }
+
+    for (path, mode) in dirs.iter().rev() {
+        remote_chmod(sftp, path, *mode)?;
+    }
+
+    Ok(stats)
+}
+
+/// Receives the remote directory to the local directory. Entries which match any of the exclude
+/// patterns are skipped. Progress of the transfer is shown by the state printer.
+pub fn fetch_dir(session: &Session, sftp: &Sftp, state_printer: &mut (StatePrinter + Send), source: &str, dest: &str, options: &DirTransferOptions) -> Result<TransferStats, String> {
+    let exclude = &options.exclude;
+    let title = format!("{} -> {}", source, dest);
+
+    let root_stat = sftp.stat(Path::new(source));
+    if root_stat.is_err() || root_stat.as_ref().unwrap().file_type() != FileType::Directory {
+        state_printer.add_one_line("FETCH DIR", &title);
+        return Err(format!("Source '{}' is not a directory", source));
+    }
+    let root_mode = root_stat.unwrap().perm.unwrap_or(0o755) & 0o7777;
+
+    let mut entries = Vec::new();
+    let scan_result = scan_remote(sftp, source.trim_end_matches('/'), "", exclude, &mut entries);
+    if scan_result.is_err() {
+        state_printer.add_one_line("FETCH DIR", &title);
+        return Err(scan_result.err().unwrap());
+    }
+
+    let mut progress = start_progress(state_printer, "FETCH DIR", &title, &entries);
+    let mut stats = TransferStats { files: 0, dirs: 0, symlinks: 0, skipped: 0, bytes: 0 };
+    let mut limiter = RateLimiter::new(options.rate);
+
+    // Directories are created writable, them final modes are set after the transfer of content
+    let mut dirs = vec![(PathBuf::from(dest), root_mode)];
+    local_mkdir(Path::new(dest))?;
+    for entry in entries {
+        let remote_path = format!("{}/{}", source.trim_end_matches('/'), entry.path);
+        let local_path = Path::new(dest).join(&entry.path);
+
+        match entry.kind {
+            EntryKind::Dir => {
+                local_mkdir(&local_path)?;
+                dirs.push((local_path, entry.mode));
+                stats.dirs += 1;
+            },
+            EntryKind::Symlink(target) => {
+                if fs::symlink_metadata(&local_path).is_ok() {
+                    let remove_result = fs::remove_file(&local_path);
+                    if remove_result.is_err() {
+                        return Err(format!("Unable to remove '{}': {}", local_path.display(), remove_result.err().unwrap()));
+                    }
+                }
+                let symlink_result = symlink(&target, &local_path);
+                if symlink_result.is_err() {
+                    return Err(format!("Unable to create symlink '{}': {}", local_path.display(), symlink_result.err().unwrap()));
+                }
+                stats.symlinks += 1;
+            },
+            EntryKind::File(size) => {
+                if options.skip_unchanged && is_local_file(&local_path) && is_unchanged(session, sftp, &local_path, &remote_path)? {
+                    local_chmod(&local_path, entry.mode)?;
+                    stats.skipped += 1;
+                    progress.add_bytes(size);
+                    progress.add_file();
+                    continue;
+                }
+
+                let remote_file = sftp.open(Path::new(&remote_path));
+                if remote_file.is_err() {
+                    return Err(format!("Unable to open source file '{}': {}", remote_path, remote_file.err().unwrap()));
+                }
+                let mut remote_file = remote_file.unwrap();
+
+                let mut file = local_create(&local_path)?;
+
+                let mut buf = [0; BLOCK_SIZE];
+                loop {
+                    let read = remote_file.read(&mut buf);
+                    if read.is_err() {
+                        return Err(format!("Unable to read source file '{}': {}", remote_path, read.err().unwrap()));
+                    }
+                    let read = read.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    let written = file.write_all(&buf[..read]);
+                    if written.is_err() {
+                        return Err(format!("Unable to write dest file '{}': {}", local_path.display(), written.err().unwrap()));
+                    }
+                    stats.bytes += read as u64;
+                    limiter.consume(read as u64);
+                    progress.add_bytes(read as u64);
+                }
+
+                local_chmod(&local_path, entry.mode)?;
+                stats.files += 1;
+                progress.add_file();
+            }
+        }
+    }
+
+    for (path, mode) in dirs.iter().rev() {
+        local_chmod(path, *mode)?;
+    }
+
+    Ok(stats)
+}
+
+/// Checks the entry against the exclude patterns. Pattern without '/' is matched with the name of
+/// the entry at any level of the tree, pattern with '/' is matched with the path relative to the
+/// root of the tree. In patterns '*' matches any sequence of chars except '/' and '?' matches any
+/// single char except '/'.
+fn is_excluded(exclude: &[String], path: &str, name: &str) -> bool {
+    exclude.iter().any(|pattern| {
+        if pattern.contains('/') {
+            match_glob(pattern.trim_start_matches('/').as_bytes(), path.as_bytes())
+        } else {
+            match_glob(pattern.as_bytes(), name.as_bytes())
+        }
+    })
+}
+
+/// Matches value with the glob pattern
+fn match_glob(p: &[u8], v: &[u8]) -> bool {
+    if p.is_empty() {
+        return v.is_empty();
+    }
+    match p[0] {
+        b'*' => {
+            let mut i = 0;
+            loop {
+                if match_glob(&p[1..], &v[i..]) {
+                    return true;
+                }
+                if i == v.len() || v[i] == b'/' {
+                    return false;
+                }
+                i += 1;
+            }
+        },
+        b'?' => !v.is_empty() && v[0] != b'/' && match_glob(&p[1..], &v[1..]),
+        c => !v.is_empty() && v[0] == c && match_glob(&p[1..], &v[1..])
+    }
+}
+
+/// Collects entries of the local directory recursively. Directories precede their content.
+fn scan_local(root: &Path, prefix: &str, exclude: &[String], entries: &mut Vec<Entry>) -> Result<(), String> {
+    let dir = root.join(prefix);
+    let read_dir = fs::read_dir(&dir);
+    if read_dir.is_err() {
+        return Err(format!("Unable to read directory '{}': {}", dir.display(), read_dir.err().unwrap()));
+    }
+
+    let mut names: Vec<String> = read_dir.unwrap()
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+
+    for name in names {
+        let path = if prefix.is_empty() { name.clone() } else { format!("{}/{}", prefix, name) };
+        if is_excluded(exclude, &path, &name) {
+            continue;
+        }
+
+        let full_path = root.join(&path);
+        let meta = fs::symlink_metadata(&full_path);
+        if meta.is_err() {
+            return Err(format!("Unable to read attributes of '{}': {}", full_path.display(), meta.err().unwrap()));
+        }
+        let meta = meta.unwrap();
+        let mode = meta.permissions().mode() & 0o7777;
+
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(&full_path);
+            if target.is_err() {
+                return Err(format!("Unable to read symlink '{}': {}", full_path.display(), target.err().unwrap()));
+            }
+            let target = target.unwrap().to_string_lossy().into_owned();
+            entries.push(Entry { path, kind: EntryKind::Symlink(target), mode });
+        } else if meta.is_dir() {
+            entries.push(Entry { path: path.clone(), kind: EntryKind::Dir, mode });
+            scan_local(root, &path, exclude, entries)?;
+        } else if meta.is_file() {
+            entries.push(Entry { path, kind: EntryKind::File(meta.len()), mode });
+        }
+    }
+
+    Ok(())
+}
+
+/// Collects entries of the remote directory recursively. Directories precede their content.
+fn scan_remote(sftp: &Sftp, root: &str, prefix: &str, exclude: &[String], entries: &mut Vec<Entry>) -> Result<(), String> {
+    let dir = if prefix.is_empty() { String::from(root) } else { format!("{}/{}", root, prefix) };
+    let list = sftp.readdir(Path::new(&dir));
+    if list.is_err() {
+        return Err(format!("Unable to read directory '{}': {}", dir, list.err().unwrap()));
+    }
+
+    let mut list: Vec<(String, FileStat)> = list.unwrap().into_iter()
+        .filter_map(|(p, stat)| p.file_name().map(|n| (n.to_string_lossy().into_owned(), stat)))
+        .collect();
+    list.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (name, stat) in list {
+        let path = if prefix.is_empty() { name.clone() } else { format!("{}/{}", prefix, name) };
+        if is_excluded(exclude, &path, &name) {
+            continue;
+        }
+        let mode = stat.perm.unwrap_or(0o644) & 0o7777;
+
+        match stat.file_type() {
+            FileType::Symlink => {
+                let full_path = format!("{}/{}", root, path);
+                let target = sftp.readlink(Path::new(&full_path));
+                if target.is_err() {
+                    return Err(format!("Unable to read symlink '{}': {}", full_path, target.err().unwrap()));
+                }
+                let target = target.unwrap().to_string_lossy().into_owned();
+                entries.push(Entry { path, kind: EntryKind::Symlink(target), mode });
+            },
+            FileType::Directory => {
+                entries.push(Entry { path: path.clone(), kind: EntryKind::Dir, mode });
+                scan_remote(sftp, root, &path, exclude, entries)?;
+            },
+            FileType::RegularFile => {
+                entries.push(Entry { path, kind: EntryKind::File(stat.size.unwrap_or(0)), mode });
+            },
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Shows progress bar for the entries
+fn start_progress<'a>(state_printer: &'a mut (StatePrinter + Send), title: &str, body: &str, entries: &[Entry]) -> Progress<'a> {
+    let mut files_total = 0;
+    let mut bytes_total = 0;
+    for entry in entries {
+        if let EntryKind::File(size) = entry.kind {
+            files_total += 1;
+            bytes_total += size;
+        }
+    }
+
+    state_printer.add_progress(title, body, &format!("files 0/{}, bytes 0/{}", files_total, bytes_total));
+    state_printer.set_transferred(0, Some(bytes_total));
+
+    Progress {
+        state_printer,
+        files_total,
+        bytes_total,
+        files: 0,
+        bytes: 0
+    }
+}
+
+/// Creates the remote directory if it does not exist
+fn remote_mkdir(sftp: &Sftp, path: &str) -> Result<(), String> {
+    let exists = sftp.stat(Path::new(path)).map(|s| s.is_dir()).unwrap_or(false);
+    if !exists {
+        let mkdir_result = sftp.mkdir(Path::new(path), 0o755);
+        if mkdir_result.is_err() {
+            return Err(format!("Unable to create directory '{}': {}", path, mkdir_result.err().unwrap()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets mode of the remote file
+fn remote_chmod(sftp: &Sftp, path: &str, mode: u32) -> Result<(), String> {
+    let stat = FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: Some(mode),
+        atime: None,
+        mtime: None
+    };
+
+    let setstat_result = sftp.setstat(Path::new(path), stat);
+    if setstat_result.is_err() {
+        return Err(format!("Unable to set mode of '{}': {}", path, setstat_result.err().unwrap()));
+    }
+
+    Ok(())
+}
+
+/// Creates the local directory with missing parents
+fn local_mkdir(path: &Path) -> Result<(), String> {
+    let mkdir_result = fs::create_dir_all(path);
+    if mkdir_result.is_err() {
+        return Err(format!("Unable to create directory '{}': {}", path.display(), mkdir_result.err().unwrap()));
+    }
+
+    Ok(())
+}
+
+/// Checks that the local path is a regular file, symlinks are not followed
+fn is_local_file(path: &Path) -> bool {
+    fs::symlink_metadata(path).map(|meta| meta.file_type().is_file()).unwrap_or(false)
+}
+
+/// Creates or truncates the local file. An existing entry of other type (e.g. symlink left by
+/// the previous transfer) is removed first, so the content is never written through a symlink.
+fn local_create(path: &Path) -> Result<File, String> {
+    if fs::symlink_metadata(path).is_ok() && !is_local_file(path) {
+        let remove_result = fs::remove_file(path);
+        if remove_result.is_err() {
+            return Err(format!("Unable to remove '{}': {}", path.display(), remove_result.err().unwrap()));
+        }
+    }
+
+    let file = File::create(path);
+    if file.is_err() {
+        return Err(format!("Unable to open dest file '{}': {}", path.display(), file.err().unwrap()));
+    }
+
+    Ok(file.unwrap())
+}
+
+/// Sets mode of the local file
+fn local_chmod(path: &Path, mode: u32) -> Result<(), String> {
+    let chmod_result = fs::set_permissions(path, fs::Permissions::from_mode(mode));
+    if chmod_result.is_err() {
+        return Err(format!("Unable to set mode of '{}': {}", path.display(), chmod_result.err().unwrap()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(list: &[&str]) -> Vec<String> {
+        list.iter().map(|p| String::from(*p)).collect()
+    }
+
+    #[test]
+    fn match_glob_wildcards() {
+        assert!(match_glob(b"*.log", b"app.log"));
+        assert!(match_glob(b"*", b""));
+        assert!(match_glob(b"a?c", b"abc"));
+        assert!(!match_glob(b"a?c", b"ac"));
+        assert!(!match_glob(b"*.log", b"app.log.1"));
+    }
+
+    #[test]
+    fn match_glob_does_not_cross_slash() {
+        assert!(!match_glob(b"*.log", b"logs/app.log"));
+        assert!(!match_glob(b"a?b", b"a/b"));
+        assert!(match_glob(b"logs/*.log", b"logs/app.log"));
+    }
+
+    #[test]
+    fn is_excluded_by_name_at_any_level() {
+        let exclude = patterns(&["*.log", ".git"]);
+        assert!(is_excluded(&exclude, "a/b/app.log", "app.log"));
+        assert!(is_excluded(&exclude, "sub/.git", ".git"));
+        assert!(!is_excluded(&exclude, "src/main.rs", "main.rs"));
+    }
+
+    #[test]
+    fn is_excluded_by_relative_path() {
+        let exclude = patterns(&["config/local.conf", "/build/*"]);
+        assert!(is_excluded(&exclude, "config/local.conf", "local.conf"));
+        assert!(!is_excluded(&exclude, "app/config/local.conf", "local.conf"));
+        assert!(is_excluded(&exclude, "build/out", "out"));
+        assert!(!is_excluded(&exclude, "build/out/deep", "deep"));
+    }
+
+    #[test]
+    fn local_create_replaces_symlink() {
+        let dir = std::env::temp_dir().join(format!("trs-dir-transfer-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("target");
+        let dest = dir.join("dest");
+        fs::write(&target, b"target").unwrap();
+        symlink(&target, &dest).unwrap();
+
+        let result = local_create(&dest).and_then(|mut file| file.write_all(b"content").map_err(|e| e.to_string()));
+        let target_content = fs::read(&target).unwrap();
+        let dest_is_file = is_local_file(&dest);
+        let dest_content = fs::read(&dest).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(target_content, b"target");
+        assert!(dest_is_file);
+        assert_eq!(dest_content, b"content");
+    }
+}
@@ -0,0 +1,290 @@
+//! Verification of the remote host key against the OpenSSH known_hosts file
+
+use super::connection::DEFAULT_SSH_PORT;
+use ssh2::{Session, KnownHosts, KnownHostFileKind, CheckResult};
+use sha2::{Sha256, Digest};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::path::Path;
+use std::env;
+
+/// Policy of the host key checking
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HostKeyCheck {
+
+    /// Host key must be present in the known_hosts file and must match with it
+    Strict,
+
+    /// Unknown host keys are appended to the known_hosts file, changed keys are rejected
+    AcceptNew,
+
+    /// Host key is not checked at all
+    Off
+}
+
+impl HostKeyCheck {
+
+    /// Parses policy from its command line name
+    pub fn parse(name: &str) -> Option<HostKeyCheck> {
+        match name {
+            "strict" => Some(HostKeyCheck::Strict),
+            "accept-new" => Some(HostKeyCheck::AcceptNew),
+            "off" => Some(HostKeyCheck::Off),
+            _ => None
+        }
+    }
+}
+
+/// Host key verification settings of the connection
+#[derive(Clone)]
+pub struct HostKeyVerification {
+
+    /// Checking policy
+    pub policy: HostKeyCheck,
+
+    /// Path to the known_hosts file
+    pub known_hosts: String
+}
+
+impl HostKeyVerification {
+
+    /// Creates settings with the specified policy. If known_hosts path is not specified, the
+    /// default ~/.ssh/known_hosts file is used.
+    pub fn new(policy: HostKeyCheck, known_hosts: Option<String>) -> HostKeyVerification {
+        let known_hosts = if known_hosts.is_some() {
+            known_hosts.unwrap()
+        } else {
+            let home = env::var("HOME").unwrap_or(String::from("."));
+            format!("{}/.ssh/known_hosts", home)
+        };
+
+        HostKeyVerification {
+            policy,
+            known_hosts
+        }
+    }
+
+    /// Checks the host key offered by the server of the session. The host and port is the values
+    /// which was used for connection (not resolved ip address). Returns text of the error if the
+    /// verification was failed. If the key was added to the known_hosts file, the success result
+    /// contains text of the notice about it. Problems of the known_hosts file which do not prevent
+    /// the verification are passed to the warn.
+    pub fn verify(&self, session: &Session, host: &str, port: u16, warn: &mut FnMut(&str)) -> Result<Option<String>, String> {
+        if self.policy == HostKeyCheck::Off {
+            return Ok(None);
+        }
+
+        let host_key = session.host_key();
+        if host_key.is_none() {
+            return Err(String::from("Host key verification failed: server does not offer the host key"));
+        }
+        let (key, _) = host_key.unwrap();
+        let fingerprint = fingerprint(key);
+
+        let known_hosts = session.known_hosts();
+        if known_hosts.is_err() {
+            return Err(format!("Unable to initialize known hosts: {}", known_hosts.err().unwrap()));
+        }
+        let mut known_hosts = known_hosts.unwrap();
+
+        let path = Path::new(&self.known_hosts);
+        if path.exists() {
+            let content = fs::read(path);
+            if content.is_err() {
+                return Err(format!("Unable to read known hosts file '{}': {}", self.known_hosts, content.err().unwrap()));
+            }
+            let content = String::from_utf8_lossy(&content.unwrap()).into_owned();
+
+            // Revoked keys are rejected even though the marker lines can't be read by libssh2
+            if is_revoked(&content, key) {
+                return Err(format!("Host key verification failed: key of '{}' is marked as revoked in '{}', offered key fingerprint {}",
+                                   host_entry(host, port), self.known_hosts, fingerprint));
+            }
+
+            let skipped = read_known_hosts(&mut known_hosts, &content);
+            if !skipped.is_empty() {
+                let lines: Vec<String> = skipped.iter().map(|n| n.to_string()).collect();
+                warn(&format!("Unsupported lines of known hosts file '{}' are skipped: {}", self.known_hosts, lines.join(", ")));
+            }
+        }
+
+        match known_hosts.check_port(host, port, key) {
+            CheckResult::Match => Ok(None),
+            CheckResult::Mismatch => {
+                Err(format!("Host key verification failed: key of '{}' does not match the key in '{}', offered key fingerprint {}. It is possible that someone is doing something nasty!",
+                            host_entry(host, port), self.known_hosts, fingerprint))
+            },
+            CheckResult::NotFound => {
+                if self.policy == HostKeyCheck::Strict {
+                    return Err(format!("Host key verification failed: '{}' is not found in '{}', offered key fingerprint {}",
+                                       host_entry(host, port), self.known_hosts, fingerprint));
+                }
+
+                let append_result = append_known_host(path, &host_entry(host, port), key);
+                if append_result.is_err() {
+                    return Err(format!("Unable to add host key to '{}': {}", self.known_hosts, append_result.err().unwrap()));
+                }
+
+                Ok(Some(format!("Permanently added '{}' ({}) to the list of known hosts", host_entry(host, port), fingerprint)))
+            },
+            CheckResult::Failure => {
+                Err(format!("Host key verification failed: unable to check key of '{}', offered key fingerprint {}", host_entry(host, port), fingerprint))
+            }
+        }
+    }
+}
+
+/// Returns fingerprint of the key in the OpenSSH format - 'SHA256:<unpadded base64 digest>'
+pub fn fingerprint(key: &[u8]) -> String {
+    let digest = Sha256::digest(key);
+    let encoded = base64::encode_config(&digest, base64::STANDARD_NO_PAD);
+
+    format!("SHA256:{}", encoded)
+}
+
+/// Adds entries of the known_hosts file content. As in the OpenSSH, lines which can't be parsed
+/// (markers, unsupported key types) are skipped. Returns numbers of the skipped lines.
+fn read_known_hosts(known_hosts: &mut KnownHosts, content: &str) -> Vec<usize> {
+    let mut skipped = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Markers aren't supported by libssh2, they would be taken for host patterns
+        if line.starts_with('@') || known_hosts.read_str(line, KnownHostFileKind::OpenSSH).is_err() {
+            skipped.push(i + 1);
+        }
+    }
+
+    skipped
+}
+
+/// Checks that the key is marked as revoked by some '@revoked' line of the known_hosts content
+fn is_revoked(content: &str, key: &[u8]) -> bool {
+    content.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() >= 4 && fields[0] == "@revoked" && base64::decode(fields[3]).map(|k| k == key).unwrap_or(false)
+    })
+}
+
+/// Returns host name in the form which is used in the known_hosts file
+fn host_entry(host: &str, port: u16) -> String {
+    if port == DEFAULT_SSH_PORT {
+        String::from(host)
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// Returns type name of the key (ssh-rsa, ssh-ed25519 and etc). Type name is the first string
+/// in the key blob.
+fn key_type(key: &[u8]) -> Option<String> {
+    if key.len() < 4 {
+        return None;
+    }
+    let len = ((key[0] as usize) << 24) | ((key[1] as usize) << 16) | ((key[2] as usize) << 8) | key[3] as usize;
+    if key.len() < 4 + len {
+        return None;
+    }
+
+    String::from_utf8(key[4..4 + len].to_vec()).ok()
+}
+
+/// Appends the host key line to the end of the known_hosts file. Creates file and its directory
+/// if them does not exist.
+fn append_known_host(path: &Path, host: &str, key: &[u8]) -> std::io::Result<()> {
+    let key_type = key_type(key);
+    if key_type.is_none() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unknown key type"));
+    }
+    let key_type = key_type.unwrap();
+
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    let needs_newline = if path.exists() {
+        let content = fs::read(path)?;
+        !content.is_empty() && content[content.len() - 1] != b'\n'
+    } else {
+        false
+    };
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?;
+
+    if needs_newline {
+        file.write_all(b"\n")?;
+    }
+    file.write_all(format!("{} {} {}\n", host, key_type, base64::encode(key)).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ed25519_key() -> Vec<u8> {
+        let mut key = vec![0, 0, 0, 11];
+        key.extend_from_slice(b"ssh-ed25519");
+        key.extend_from_slice(&[0, 0, 0, 32]);
+        key.extend(0..32u8);
+        key
+    }
+
+    #[test]
+    fn fingerprint_is_unpadded_base64_of_sha256() {
+        assert_eq!(fingerprint(&[]), "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU");
+        assert_eq!(fingerprint(&ed25519_key()), "SHA256:ZkAslGjFiUHdGf/WUL8rQvkib4PTvQatUV0OUQSncCA");
+    }
+
+    #[test]
+    fn host_entry_default_port() {
+        assert_eq!(host_entry("db01.internal", DEFAULT_SSH_PORT), "db01.internal");
+    }
+
+    #[test]
+    fn host_entry_custom_port() {
+        assert_eq!(host_entry("db01.internal", 2222), "[db01.internal]:2222");
+        assert_eq!(host_entry("::1", 2222), "[::1]:2222");
+    }
+
+    #[test]
+    fn key_type_from_blob() {
+        assert_eq!(key_type(&ed25519_key()), Some(String::from("ssh-ed25519")));
+        assert_eq!(key_type(&[0, 0]), None);
+        assert_eq!(key_type(&[0, 0, 0, 11, b's', b's', b'h']), None);
+    }
+
+    #[test]
+    fn unparsable_lines_are_skipped() {
+        let key = ed25519_key();
+        let content = format!("# comment\n\
+                               @cert-authority *.example.com ssh-ed25519 {0}\n\
+                               broken line\n\
+                               \n\
+                               example.com ssh-ed25519 {0}\n", base64::encode(&key));
+
+        let session = Session::new().unwrap();
+        let mut known_hosts = session.known_hosts().unwrap();
+        assert_eq!(read_known_hosts(&mut known_hosts, &content), vec![2, 3]);
+        match known_hosts.check_port("example.com", 22, &key) {
+            CheckResult::Match => (),
+            _ => panic!("valid entry is not loaded"),
+        }
+    }
+
+    #[test]
+    fn revoked_key() {
+        let key = ed25519_key();
+        let content = format!("@revoked * ssh-ed25519 {}\n", base64::encode(&key));
+        assert!(is_revoked(&content, &key));
+        assert!(!is_revoked(&content, &key[1..]));
+        assert!(!is_revoked("@revoked * ssh-ed25519 not-base64\n", &key));
+    }
+}
@@ -3,4 +3,14 @@
 
 pub mod script_runtime;
 pub mod connection;
-pub mod ssh_thread_safe;
\ No newline at end of file
+pub mod ssh_thread_safe;
+pub mod host_key;
+pub mod ssh_config;
+pub mod tunnel;
+pub mod proxy_command;
+pub mod sftp;
+pub mod dir_transfer;
+pub mod delta;
+pub mod rate_limit;
+pub mod shell_reader;
+pub mod shell_setup;
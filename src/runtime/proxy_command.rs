@@ -0,0 +1,80 @@
+//! Transport of the ssh session over stdin/stdout of a local process, as with the OpenSSH
+//! ProxyCommand option
+
+use std::io::prelude::*;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Child, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Running proxy process. The process is killed when this object is dropped.
+pub struct ProxyCommand {
+
+    /// Process of the command
+    child: Child,
+
+    /// Collected stderr output of the process
+    stderr: Arc<Mutex<String>>
+}
+
+impl ProxyCommand {
+
+    /// Spawns the command through the shell. As in the OpenSSH, the shell is replaced by the
+    /// command with exec, so the command itself is killed with the process. Stdin and stdout of
+    /// the process are connected to the socket, other end of which is returned alongside the
+    /// process object. This end is used as the stream of the ssh session.
+    pub fn spawn(command: &str) -> Result<(ProxyCommand, UnixStream), String> {
+        let pair = UnixStream::pair();
+        if pair.is_err() {
+            return Err(format!("Unable to create proxy command socket: {}", pair.err().unwrap()));
+        }
+        let (local, remote) = pair.unwrap();
+
+        let remote_out = remote.try_clone();
+        if remote_out.is_err() {
+            return Err(format!("Unable to create proxy command socket: {}", remote_out.err().unwrap()));
+        }
+        let remote_out = remote_out.unwrap();
+
+        let child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(format!("exec {}", command))
+            .stdin(Stdio::from(OwnedFd::from(remote)))
+            .stdout(Stdio::from(OwnedFd::from(remote_out)))
+            .stderr(Stdio::piped())
+            .spawn();
+        if child.is_err() {
+            return Err(format!("Unable to start proxy command '{}': {}", command, child.err().unwrap()));
+        }
+        let mut child = child.unwrap();
+
+        // Stderr is collected in the background, it is used in the error messages
+        let stderr = Arc::new(Mutex::new(String::new()));
+        let mut pipe = child.stderr.take().unwrap();
+        let stderr_c = stderr.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            loop {
+                match pipe.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => stderr_c.lock().unwrap().push_str(&String::from_utf8_lossy(&buf[..n]))
+                }
+            }
+        });
+
+        Ok((ProxyCommand { child, stderr }, local))
+    }
+
+    /// Returns the stderr output of the process collected to the current moment
+    pub fn error_output(&self) -> String {
+        String::from(self.stderr.lock().unwrap().trim())
+    }
+}
+
+impl Drop for ProxyCommand {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,134 @@
+//! Limiting of the bandwidth used by the file transfers
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Throttles the transfer loop to the budget of bytes per second. The budget is kept over the whole
+/// transfer, so short stalls of the network are compensated by the following blocks.
+pub struct RateLimiter {
+
+    /// Budget in bytes per second. None means that the transfer is not limited
+    rate: Option<u64>,
+
+    /// Start of the transfer
+    start: Instant,
+
+    /// Count of the transferred bytes
+    bytes: u64
+}
+
+impl RateLimiter {
+
+    /// Creates limiter with the budget in bytes per second. Zero budget means no limit.
+    pub fn new(rate: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            rate: rate.filter(|r| *r > 0),
+            start: Instant::now(),
+            bytes: 0
+        }
+    }
+
+    /// Accounts the transferred bytes and sleeps while the transfer is ahead of the budget
+    pub fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if self.rate.is_none() {
+            return;
+        }
+
+        let expected = Duration::from_secs_f64(self.bytes as f64 / self.rate.unwrap() as f64);
+        let elapsed = self.start.elapsed();
+        if expected > elapsed {
+            thread::sleep(expected - elapsed);
+        }
+    }
+}
+
+/// Parses rate in bytes per second. As in the curl --limit-rate option, the number may have the
+/// suffix K, M or G (powers of 1024).
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024),
+        Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1)
+    };
+
+    // Too large value is malformed as well as the value which is not a number
+    let rate = number.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier));
+    if rate.is_none() {
+        return Err(format!("Invalid rate '{}', expected number of bytes per second with optional suffix K, M or G", value));
+    }
+
+    Ok(rate.unwrap())
+}
+
+/// Formats rate in bytes per second to the human readable form
+pub fn format_rate(rate: u64) -> String {
+    if rate >= 1024 * 1024 {
+        format!("{:.1} MiB/s", rate as f64 / (1024.0 * 1024.0))
+    } else if rate >= 1024 {
+        format!("{:.1} KiB/s", rate as f64 / 1024.0)
+    } else {
+        format!("{} B/s", rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_plain_bytes() {
+        assert_eq!(parse_rate("500"), Ok(500));
+        assert_eq!(parse_rate(" 0 "), Ok(0));
+    }
+
+    #[test]
+    fn parse_rate_suffixes_any_case() {
+        assert_eq!(parse_rate("100K"), Ok(100 * 1024));
+        assert_eq!(parse_rate("100k"), Ok(100 * 1024));
+        assert_eq!(parse_rate("2M"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_rate("1g"), Ok(1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn parse_rate_invalid() {
+        assert!(parse_rate("").is_err());
+        assert!(parse_rate("K").is_err());
+        assert!(parse_rate("1.5M").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("10T").is_err());
+        assert!(parse_rate("10 KB").is_err());
+    }
+
+    #[test]
+    fn parse_rate_overflow() {
+        assert!(parse_rate("99999999999G").is_err());
+        assert!(parse_rate("18446744073709551616").is_err());
+        assert_eq!(parse_rate("18446744073709551615"), Ok(u64::max_value()));
+    }
+
+    #[test]
+    fn format_rate_units() {
+        assert_eq!(format_rate(512), "512 B/s");
+        assert_eq!(format_rate(1536), "1.5 KiB/s");
+        assert_eq!(format_rate(3 * 1024 * 1024), "3.0 MiB/s");
+    }
+
+    #[test]
+    fn limiter_without_rate_does_not_sleep() {
+        let mut limiter = RateLimiter::new(Some(0));
+        let start = Instant::now();
+        limiter.consume(1024 * 1024 * 1024);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn limiter_sleeps_ahead_of_budget() {
+        let mut limiter = RateLimiter::new(Some(1000));
+        let start = Instant::now();
+        limiter.consume(200);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
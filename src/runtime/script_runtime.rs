@@ -3,16 +3,80 @@
 use crate::tsafe::TSafe;
 use crate::out_logger::OutLogger;
 use crate::state_printer::state_printer::StatePrinter;
-use super::connection::{Connection, KeyAuthentication, SimpleAuthentication};
+use super::connection::{Connection, ConnectionParams, KeyAuthentication, SimpleAuthentication, AgentAuthentication, InteractiveAuthentication, InteractiveResponder, InteractivePrompt, Transport, CONNECT_TIMEOUT, DEFAULT_TERM, DEFAULT_SSH_PORT};
+use super::host_key::HostKeyVerification;
+use super::ssh_config::{SshConfig, local_user, expand_tokens};
+use super::proxy_command::ProxyCommand;
 use regex::Regex;
 use std::marker::PhantomData;
 use std::io::prelude::*;
 use std::io;
 use std::sync::{Arc, Mutex};
+use std::any::Any;
+use std::env;
+use std::path::Path;
 
 /// Size of block of data used with file operations
 pub const BLOCK_SIZE: usize = 32700;
 
+/// Connection options specified by the script
+pub struct ConnectOptions {
+
+    /// Address of the remote host in form host[:port]
+    pub addr: String,
+
+    /// Port of the remote host. If specified, it overrides port from the address
+    pub port: Option<u16>,
+
+    /// Remote user name. If not specified, user from the ssh config or the local user name is used
+    pub user: Option<String>,
+
+    /// Authentication methods in order of trying. If empty, the ssh agent and identity files from
+    /// the ssh config are used
+    pub auth: Vec<Box<Any + Send>>,
+
+    /// Custom shell prompt regexp
+    pub prompt: Option<String>,
+
+    /// Timeout of the tcp connection establishing in seconds
+    pub timeout: Option<u64>,
+
+    /// Terminal type of the shell pty
+    pub term: Option<String>,
+
+    /// Connection used as jump host. If specified, the connection is established through the
+    /// tunnel opened on it. Otherwise, jump hosts from the ProxyJump option of the ssh config are
+    /// used
+    pub via: Option<TSafe<Connection>>,
+
+    /// Command which stdin/stdout is used as the connection to the host. OpenSSH tokens %h, %p,
+    /// %r, %n and %% are expanded. If not specified, ProxyCommand of the ssh config is used
+    pub proxy_command: Option<String>,
+
+    /// Default timeout of the commands executed in the shell in seconds. If not specified, the
+    /// --exec-timeout value is used
+    pub exec_timeout: Option<u64>
+}
+
+impl ConnectOptions {
+
+    /// Creates options with the single authentication method. Other options has default values
+    pub fn new(addr: String, user: String, atk: Box<Any + Send>, prompt: Option<String>) -> ConnectOptions {
+        ConnectOptions {
+            addr,
+            port: None,
+            user: Some(user),
+            auth: vec![atk],
+            prompt,
+            timeout: None,
+            term: None,
+            via: None,
+            proxy_command: None,
+            exec_timeout: None
+        }
+    }
+}
+
 pub struct ScriptRuntime {
 
     /// State printer instance
@@ -24,65 +88,240 @@ pub struct ScriptRuntime {
     /// Default shell prompt
     default_prompt: Regex,
 
+    /// Host key verification settings for new connections
+    host_keys: HostKeyVerification,
+
+    /// OpenSSH client config
+    ssh_config: SshConfig,
+
+    /// Default limit of the file transfers rate in bytes per second
+    limit_rate: Option<u64>,
+
+    /// Default timeout of the commands executed in the shell in seconds
+    exec_timeout: Option<u64>,
+
     /// Connections list
     connections: Vec<TSafe<Connection>>
 }
 
 impl  ScriptRuntime {
-    pub fn new(state_printer: TSafe<StatePrinter + Send>, out_logger: OutLogger) -> ScriptRuntime {
+    pub fn new(state_printer: TSafe<StatePrinter + Send>, out_logger: OutLogger, host_keys: HostKeyVerification, ssh_config: SshConfig, limit_rate: Option<u64>, exec_timeout: Option<u64>) -> ScriptRuntime {
         ScriptRuntime {
             state_printer,
             out_logger,
             default_prompt: Regex::new("\\$ ").unwrap(),
+            host_keys,
+            ssh_config,
+            limit_rate,
+            exec_timeout,
             connections: Vec::new()
         }
     }
 
-    /// Creates new connection through ssh bridge use user/password authentication method
-    pub fn connect_ssh_simple(&mut self, addr: String, user: String, password: String, prompt: Option<String>) -> TSafe<Connection> {
-        let prompt = if prompt.is_some() {
-            let p = Regex::new(&prompt.unwrap());
-            if p.is_ok() {
-                Some(p.unwrap())
-            } else {
-                None
+    /// Creates new connection through ssh bridge with the specified options. If options contains
+    /// the keyboard-interactive authentication method, each prompt of the server is passed to the
+    /// responder. If responder is not specified, answers will be requested from the user. Runtime
+    /// is not locked while connection is established, because responder may use it.
+    pub fn connect(runtime: &TSafe<ScriptRuntime>, options: ConnectOptions, responder: Option<InteractiveResponder>) -> TSafe<Connection> {
+        Self::connect_host(runtime, options, responder, false)
+    }
+
+    /// Internal API - creates new connection. Jump flag indicates that the connection is created
+    /// as the jump host for another connection. Such connections does not open the shell and does
+    /// not use ProxyJump option of the ssh config.
+    fn connect_host(runtime: &TSafe<ScriptRuntime>, options: ConnectOptions, responder: Option<InteractiveResponder>, jump: bool) -> TSafe<Connection> {
+        let (state_printer, out_logger, host_keys, limit_rate, exec_timeout, prompt, mut options, keepalive, proxy_jump) = {
+            let runtime = runtime.lock().unwrap();
+            let prompt = runtime.compile_prompt(options.prompt.clone());
+            let (options, keepalive, proxy_jump) = runtime.apply_ssh_config(options);
+            (runtime.state_printer.clone(), runtime.out_logger.clone(), runtime.host_keys.clone(), runtime.limit_rate, runtime.exec_timeout, prompt, options, keepalive, proxy_jump)
+        };
+
+        // Connect to the jump hosts chain from the ssh config, if the script does not specify
+        // jump host or proxy command explicitly
+        let mut via = options.via.take();
+        if via.is_none() && options.proxy_command.is_none() && !jump && proxy_jump.is_some() && proxy_jump.as_ref().unwrap() != "none" {
+            for hop in proxy_jump.unwrap().split(',') {
+                let (user, addr) = match hop.rfind('@') {
+                    Some(i) => (Some(String::from(&hop[..i])), String::from(&hop[i + 1..])),
+                    None => (None, String::from(hop))
+                };
+                let hop_options = ConnectOptions {
+                    addr,
+                    port: None,
+                    user,
+                    auth: Vec::new(),
+                    prompt: None,
+                    timeout: None,
+                    term: None,
+                    via,
+                    proxy_command: None,
+                    exec_timeout: None
+                };
+                via = Some(Self::connect_host(runtime, hop_options, None, true));
             }
+        }
+
+        let transport = if via.is_some() {
+            let target = Connection::split_addr(&options.addr);
+            let tunnel = if target.is_ok() {
+                let (host, port) = target.unwrap();
+                let port = options.port.or(port).unwrap_or(DEFAULT_SSH_PORT);
+                via.unwrap().lock().unwrap().open_tunnel(host, port)
+            } else {
+                Err(target.err().unwrap())
+            };
+            Transport::Tunnel(tunnel.map_err(|e| format!("Unable to connect through jump host: {}", e)))
+        } else if options.proxy_command.is_some() {
+            Transport::Command(ProxyCommand::spawn(&options.proxy_command.unwrap()))
         } else {
-            Some(self.default_prompt.clone())
+            Transport::Tcp
         };
 
-        let atk = Box::new(SimpleAuthentication { user, password });
-        let conn = Connection::new(addr, atk, self.state_printer.clone(), self.out_logger.clone(), prompt);
+        let responder = if responder.is_some() {
+            responder.unwrap()
+        } else {
+            let state_printer = state_printer.clone();
+            Box::new(move |request: &InteractivePrompt| {
+                if !request.instructions.is_empty() {
+                    state_printer.lock().unwrap().print_to_current(&request.instructions);
+                }
+                Ok(request.prompts.iter()
+                    .map(|(text, echo)| Self::read_input(&state_printer, text, !*echo))
+                    .collect())
+            })
+        };
+
+        let params = ConnectionParams {
+            addr: options.addr,
+            port: options.port,
+            user: options.user.unwrap_or(local_user()),
+            transport,
+            auth: options.auth,
+            prompt,
+            custom_prompt: options.prompt.is_some(),
+            timeout: options.timeout.unwrap_or(CONNECT_TIMEOUT),
+            shell: !jump,
+            term: options.term.unwrap_or(String::from(DEFAULT_TERM)),
+            keepalive,
+            limit_rate,
+            exec_timeout: options.exec_timeout.or(exec_timeout),
+            host_keys
+        };
+
+        let conn = Connection::new(params, state_printer, out_logger, Some(responder));
         let conn = tsafe!(conn);
-        self.connections.push(conn.clone());
+        runtime.lock().unwrap().connections.push(conn.clone());
 
         conn
     }
 
-    /// Creates new connection through ssh bridge use key authentication method
-    pub fn connect_ssh_key(&mut self, addr: String, user: String, private_key: String, prompt: Option<String>, passphrase: Option<String>, public_key: Option<String>) -> TSafe<Connection> {
-        let prompt = if prompt.is_some() {
-            let p = Regex::new(&prompt.unwrap());
-            if p.is_ok() {
-                Some(p.unwrap())
-            } else {
-                None
-            }
-        } else {
-            Some(self.default_prompt.clone())
-        };
+    /// Creates new connection through ssh bridge use user/password authentication method
+    pub fn connect_ssh_simple(runtime: &TSafe<ScriptRuntime>, addr: String, user: String, password: String, prompt: Option<String>) -> TSafe<Connection> {
+        let atk = Box::new(SimpleAuthentication { password });
 
+        Self::connect(runtime, ConnectOptions::new(addr, user, atk, prompt), None)
+    }
+
+    /// Creates new connection through ssh bridge use key authentication method
+    pub fn connect_ssh_key(runtime: &TSafe<ScriptRuntime>, addr: String, user: String, private_key: String, prompt: Option<String>, passphrase: Option<String>, public_key: Option<String>) -> TSafe<Connection> {
         let atk = Box::new(KeyAuthentication {
-            user,
             private_key,
             public_key,
             passphrase
         });
-        let conn = Connection::new(addr, atk, self.state_printer.clone(), self.out_logger.clone(), prompt);
-        let conn = tsafe!(conn);
-        self.connections.push(conn.clone());
 
-        conn
+        Self::connect(runtime, ConnectOptions::new(addr, user, atk, prompt), None)
+    }
+
+    /// Creates new connection through ssh bridge use identities of the ssh agent
+    pub fn connect_ssh_agent(runtime: &TSafe<ScriptRuntime>, addr: String, user: String, prompt: Option<String>) -> TSafe<Connection> {
+        let atk = Box::new(AgentAuthentication {});
+
+        Self::connect(runtime, ConnectOptions::new(addr, user, atk, prompt), None)
+    }
+
+    /// Creates new connection through ssh bridge use keyboard-interactive authentication method.
+    /// See connect for details about responder.
+    pub fn connect_ssh_interactive(runtime: &TSafe<ScriptRuntime>, addr: String, user: String, prompt: Option<String>, responder: Option<InteractiveResponder>) -> TSafe<Connection> {
+        let atk = Box::new(InteractiveAuthentication {});
+
+        Self::connect(runtime, ConnectOptions::new(addr, user, atk, prompt), responder)
+    }
+
+    /// Internal API - fills options which was not specified by the script from the ssh config
+    /// section of the host. If authentication methods is not specified, the ssh agent (if it is
+    /// running) and existing identity files are used. Identity files are taken from the ssh config
+    /// or, if there are no such files, the default ~/.ssh/id_* files are used. Returns updated
+    /// options, the keepalive interval and the jump hosts chain. Tokens of the proxy command are
+    /// expanded.
+    fn apply_ssh_config(&self, options: ConnectOptions) -> (ConnectOptions, Option<u32>, Option<String>) {
+        let mut options = options;
+        let mut keepalive = None;
+        let mut proxy_jump = None;
+        let mut identity_files = Vec::new();
+
+        let host_port = Connection::split_addr(&options.addr);
+        if host_port.is_ok() {
+            let (host, port) = host_port.unwrap();
+            let config = self.ssh_config.resolve(&host);
+
+            options.port = options.port.or(port).or(config.port);
+            if config.host_name.is_some() {
+                options.addr = config.host_name.unwrap();
+            }
+            options.user = options.user.or(config.user);
+            if options.via.is_none() {
+                options.proxy_command = options.proxy_command.or(config.proxy_command);
+            }
+            if options.proxy_command.is_some() {
+                let command = options.proxy_command.take().unwrap();
+                if command != "none" {
+                    let host_name = Connection::split_addr(&options.addr).map(|(h, _)| h).unwrap_or(options.addr.clone());
+                    let port = options.port.unwrap_or(DEFAULT_SSH_PORT).to_string();
+                    let user = options.user.clone().unwrap_or(local_user());
+                    options.proxy_command = Some(expand_tokens(&command, &host, &host_name, &port, &user));
+                }
+            }
+            options.timeout = options.timeout.or(config.connect_timeout);
+            keepalive = config.server_alive_interval.filter(|i| *i > 0);
+            proxy_jump = config.proxy_jump;
+            identity_files = config.identity_files;
+        }
+
+        if options.auth.is_empty() {
+            if env::var("SSH_AUTH_SOCK").is_ok() {
+                options.auth.push(Box::new(AgentAuthentication {}));
+            }
+
+            if identity_files.is_empty() {
+                let home = env::var("HOME").unwrap_or(String::from("."));
+                identity_files = ["id_rsa", "id_ecdsa", "id_ed25519"].iter()
+                    .map(|f| format!("{}/.ssh/{}", home, f))
+                    .collect();
+            }
+            for file in identity_files {
+                if Path::new(&file).exists() {
+                    options.auth.push(Box::new(KeyAuthentication {
+                        private_key: file,
+                        public_key: None,
+                        passphrase: None
+                    }));
+                }
+            }
+        }
+
+        (options, keepalive, proxy_jump)
+    }
+
+    /// Internal API - compiles the custom prompt. If prompt is not specified, default prompt will
+    /// be returned. If prompt is not a valid regexp, None will be returned.
+    fn compile_prompt(&self, prompt: Option<String>) -> Option<Regex> {
+        if prompt.is_some() {
+            Regex::new(&prompt.unwrap()).ok()
+        } else {
+            Some(self.default_prompt.clone())
+        }
     }
 
     /// Prints text line to the current state
@@ -92,25 +331,43 @@ impl  ScriptRuntime {
 
     /// Requests input from user
     pub fn read(&self, prompt: &str) -> String {
-        self.state_printer.lock().unwrap().print_read_request(prompt);
-        let mut buffer = String::new();
-        let stdin = io::stdin();
-        let mut handle = stdin.lock();
-        handle.read_line(&mut buffer).unwrap();
-        String::from(&buffer[..buffer.len() - 1])
+        Self::read_input(&self.state_printer, prompt, false)
     }
 
     /// Requests input from user
     pub fn read_pass(&self, prompt: &str) -> String {
-        self.state_printer.lock().unwrap().print_read_request(prompt);
-        let buffer = rpassword::read_password().unwrap();
-        String::from(&buffer[..buffer.len()])
+        Self::read_input(&self.state_printer, prompt, true)
+    }
+
+    /// Internal API - requests input from user. If hidden flag is set, input is read in the
+    /// password mode
+    fn read_input(state_printer: &TSafe<StatePrinter + Send>, prompt: &str, hidden: bool) -> String {
+        state_printer.lock().unwrap().print_read_request(prompt);
+        if hidden {
+            let buffer = rpassword::read_password().unwrap();
+            String::from(&buffer[..buffer.len()])
+        } else {
+            let mut buffer = String::new();
+            let stdin = io::stdin();
+            let mut handle = stdin.lock();
+            handle.read_line(&mut buffer).unwrap();
+            String::from(&buffer[..buffer.len() - 1])
+        }
     }
 
     /// Closes all opened connection. This is the app level destructor of the runtime
     pub fn close_connections(&mut self) {
         while self.connections.len() > 0 {
-            self.connections.pop().unwrap().lock().unwrap().close();
+            let conn = self.connections.pop().unwrap();
+            let mut conn = conn.lock().unwrap();
+
+            // Active port forwardings are closed explicitly, so the user can see them
+            for (id, description) in conn.forwards() {
+                self.state_printer.lock().unwrap().add_one_line("CLOSE FORWARD", &description);
+                conn.close_forward(id);
+            }
+
+            conn.close();
         }
     }
 }
@@ -0,0 +1,339 @@
+//! Operations of the SFTP subsystem. Sftp session lives in the connection thread, the operations
+//! are passed to it as messages.
+
+use ssh2::{Session, Sftp, File, FileStat, FileType, OpenFlags, OpenType, ErrorCode};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+/// Mode of the directories created by mkdir if it is not specified
+pub const DEFAULT_DIR_MODE: i32 = 0o755;
+
+/// Mode of the files created by write and open if it is not specified
+pub const DEFAULT_FILE_MODE: i32 = 0o644;
+
+/// Maximum count of bytes read from the opened file at once. Larger reads are cut to it, so the
+/// script can't exhaust the memory by the buffer size
+pub const MAX_READ_SIZE: usize = 1024 * 1024;
+
+/// Operation of the sftp session
+pub enum SftpOp {
+
+    /// Opens the subsystem, if it is not opened yet
+    Init,
+
+    /// Lists the directory
+    List(String),
+
+    /// Returns attributes of the file, following symlinks
+    Stat(String),
+
+    /// Returns attributes of the file, not following symlinks
+    Lstat(String),
+
+    /// Creates the directory with the mode
+    Mkdir(String, i32),
+
+    /// Removes the empty directory
+    Rmdir(String),
+
+    /// Removes the file
+    Remove(String),
+
+    /// Renames the file. Destination is overwritten if it exists
+    Rename(String, String),
+
+    /// Creates the symlink at the path (second) pointing to the target (first)
+    Symlink(String, String),
+
+    /// Returns target of the symlink
+    Readlink(String),
+
+    /// Changes mode of the file
+    Chmod(String, i32),
+
+    /// Reads the whole file
+    Read(String),
+
+    /// Writes the whole file with the mode, replacing it if it exists
+    Write(String, Vec<u8>, i32),
+
+    /// Opens the file in the mode ('r', 'w', 'a', 'r+', 'w+', 'a+') and returns its handle
+    Open(String, String),
+
+    /// Reads up to the specified count of bytes (but no more than MAX_READ_SIZE) from the opened
+    /// file
+    FileRead(usize, usize),
+
+    /// Writes data to the opened file
+    FileWrite(usize, Vec<u8>),
+
+    /// Sets position of the opened file
+    FileSeek(usize, u64),
+
+    /// Closes the opened file
+    FileClose(usize)
+}
+
+/// Successful result of the sftp operation
+pub enum SftpValue {
+    None,
+    Stat(FileInfo),
+    List(Vec<FileInfo>),
+    Path(String),
+    Data(Vec<u8>),
+    Handle(usize)
+}
+
+/// Error of the sftp operation
+pub struct SftpError {
+
+    /// SFTP status code (2 - no such file, 3 - permission denied and etc), if the error was
+    /// reported by the server
+    pub code: Option<i32>,
+
+    /// Text of the error
+    pub text: String
+}
+
+/// Attributes of the remote file
+pub struct FileInfo {
+
+    /// Name of the file, it is set only for entries of the directory list
+    pub name: Option<String>,
+
+    /// Type of the file - 'file', 'dir', 'symlink' or 'other'
+    pub kind: String,
+    pub size: Option<u64>,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub atime: Option<u64>,
+    pub mtime: Option<u64>
+}
+
+impl FileInfo {
+
+    /// Creates info from the attributes returned by the server
+    fn new(name: Option<String>, stat: &FileStat) -> FileInfo {
+        let kind = match stat.file_type() {
+            FileType::RegularFile => "file",
+            FileType::Directory => "dir",
+            FileType::Symlink => "symlink",
+            _ => "other"
+        };
+
+        FileInfo {
+            name,
+            kind: String::from(kind),
+            size: stat.size,
+            mode: stat.perm.map(|p| p & 0o7777),
+            uid: stat.uid,
+            gid: stat.gid,
+            atime: stat.atime,
+            mtime: stat.mtime
+        }
+    }
+}
+
+/// Opened sftp subsystem with the files opened through it
+pub struct SftpSession {
+    sftp: Sftp,
+    files: HashMap<usize, File>,
+    files_counter: usize
+}
+
+impl SftpSession {
+
+    /// Opens sftp subsystem on the session
+    pub fn new(session: &Session) -> Result<SftpSession, String> {
+        let sftp = session.sftp();
+        if sftp.is_err() {
+            return Err(format!("Unable to open sftp subsystem: {}", sftp.err().unwrap()));
+        }
+
+        Ok(SftpSession {
+            sftp: sftp.unwrap(),
+            files: HashMap::new(),
+            files_counter: 0
+        })
+    }
+
+    /// Returns the sftp subsystem
+    pub fn sftp(&self) -> &Sftp {
+        &self.sftp
+    }
+
+    /// Returns description of the operation for the state printer, or None if the operation must
+    /// not be printed (operations with opened files)
+    pub fn describe(op: &SftpOp) -> Option<(&'static str, String)> {
+        match op {
+            SftpOp::List(path) => Some(("SFTP LIST", path.clone())),
+            SftpOp::Stat(path) => Some(("SFTP STAT", path.clone())),
+            SftpOp::Lstat(path) => Some(("SFTP LSTAT", path.clone())),
+            SftpOp::Mkdir(path, mode) => Some(("SFTP MKDIR", format!("{} {:o}", path, mode))),
+            SftpOp::Rmdir(path) => Some(("SFTP RMDIR", path.clone())),
+            SftpOp::Remove(path) => Some(("SFTP REMOVE", path.clone())),
+            SftpOp::Rename(src, dst) => Some(("SFTP RENAME", format!("{} -> {}", src, dst))),
+            SftpOp::Symlink(target, path) => Some(("SFTP SYMLINK", format!("{} -> {}", path, target))),
+            SftpOp::Readlink(path) => Some(("SFTP READLINK", path.clone())),
+            SftpOp::Chmod(path, mode) => Some(("SFTP CHMOD", format!("{} {:o}", path, mode))),
+            SftpOp::Read(path) => Some(("SFTP READ", path.clone())),
+            SftpOp::Write(path, data, _) => Some(("SFTP WRITE", format!("{} ({} bytes)", path, data.len()))),
+            SftpOp::Open(path, mode) => Some(("SFTP OPEN", format!("{} {}", path, mode))),
+            _ => None
+        }
+    }
+
+    /// Executes the operation
+    pub fn execute(&mut self, op: SftpOp) -> Result<SftpValue, SftpError> {
+        match op {
+            SftpOp::Init => Ok(SftpValue::None),
+            SftpOp::List(path) => {
+                let entries = self.sftp.readdir(Path::new(&path)).map_err(sftp_error)?;
+                let list = entries.iter()
+                    .map(|(p, stat)| {
+                        let name = p.file_name().map(|n| n.to_string_lossy().into_owned());
+                        FileInfo::new(name, stat)
+                    })
+                    .collect();
+                Ok(SftpValue::List(list))
+            },
+            SftpOp::Stat(path) => {
+                let stat = self.sftp.stat(Path::new(&path)).map_err(sftp_error)?;
+                Ok(SftpValue::Stat(FileInfo::new(None, &stat)))
+            },
+            SftpOp::Lstat(path) => {
+                let stat = self.sftp.lstat(Path::new(&path)).map_err(sftp_error)?;
+                Ok(SftpValue::Stat(FileInfo::new(None, &stat)))
+            },
+            SftpOp::Mkdir(path, mode) => {
+                self.sftp.mkdir(Path::new(&path), mode).map_err(sftp_error)?;
+                Ok(SftpValue::None)
+            },
+            SftpOp::Rmdir(path) => {
+                self.sftp.rmdir(Path::new(&path)).map_err(sftp_error)?;
+                Ok(SftpValue::None)
+            },
+            SftpOp::Remove(path) => {
+                self.sftp.unlink(Path::new(&path)).map_err(sftp_error)?;
+                Ok(SftpValue::None)
+            },
+            SftpOp::Rename(src, dst) => {
+                self.sftp.rename(Path::new(&src), Path::new(&dst), None).map_err(sftp_error)?;
+                Ok(SftpValue::None)
+            },
+            SftpOp::Symlink(target, path) => {
+                // OpenSSH server swaps arguments of the symlink request, so the link is created
+                // at the second path
+                self.sftp.symlink(Path::new(&target), Path::new(&path)).map_err(sftp_error)?;
+                Ok(SftpValue::None)
+            },
+            SftpOp::Readlink(path) => {
+                let target: PathBuf = self.sftp.readlink(Path::new(&path)).map_err(sftp_error)?;
+                Ok(SftpValue::Path(target.to_string_lossy().into_owned()))
+            },
+            SftpOp::Chmod(path, mode) => {
+                let stat = FileStat {
+                    size: None,
+                    uid: None,
+                    gid: None,
+                    perm: Some(mode as u32),
+                    atime: None,
+                    mtime: None
+                };
+                self.sftp.setstat(Path::new(&path), stat).map_err(sftp_error)?;
+                Ok(SftpValue::None)
+            },
+            SftpOp::Read(path) => {
+                let mut file = self.sftp.open(Path::new(&path)).map_err(sftp_error)?;
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).map_err(io_error)?;
+                Ok(SftpValue::Data(data))
+            },
+            SftpOp::Write(path, data, mode) => {
+                let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+                let mut file = self.sftp.open_mode(Path::new(&path), flags, mode, OpenType::File).map_err(sftp_error)?;
+                file.write_all(&data).map_err(io_error)?;
+                Ok(SftpValue::None)
+            },
+            SftpOp::Open(path, mode) => {
+                let flags = match &mode[..] {
+                    "r" => OpenFlags::READ,
+                    "w" => OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                    "a" => OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::APPEND,
+                    "r+" => OpenFlags::READ | OpenFlags::WRITE,
+                    "w+" => OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                    "a+" => OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::APPEND,
+                    _ => return Err(SftpError {
+                        code: None,
+                        text: format!("Unknown open mode '{}', supported modes are: r, w, a, r+, w+, a+", mode)
+                    })
+                };
+                let file = self.sftp.open_mode(Path::new(&path), flags, DEFAULT_FILE_MODE, OpenType::File).map_err(sftp_error)?;
+                self.files_counter += 1;
+                self.files.insert(self.files_counter, file);
+                Ok(SftpValue::Handle(self.files_counter))
+            },
+            SftpOp::FileRead(id, count) => {
+                let file = self.file(id)?;
+                let count = std::cmp::min(count, MAX_READ_SIZE);
+                let mut data = vec![0; count];
+                let mut read = 0;
+                while read < count {
+                    let n = file.read(&mut data[read..]).map_err(io_error)?;
+                    if n == 0 {
+                        break;
+                    }
+                    read += n;
+                }
+                data.truncate(read);
+                Ok(SftpValue::Data(data))
+            },
+            SftpOp::FileWrite(id, data) => {
+                let file = self.file(id)?;
+                file.write_all(&data).map_err(io_error)?;
+                Ok(SftpValue::None)
+            },
+            SftpOp::FileSeek(id, pos) => {
+                let file = self.file(id)?;
+                file.seek(SeekFrom::Start(pos)).map_err(io_error)?;
+                Ok(SftpValue::None)
+            },
+            SftpOp::FileClose(id) => {
+                self.files.remove(&id);
+                Ok(SftpValue::None)
+            }
+        }
+    }
+
+    /// Internal API - returns the opened file by its handle
+    fn file(&mut self, id: usize) -> Result<&mut File, SftpError> {
+        self.files.get_mut(&id).ok_or(SftpError {
+            code: None,
+            text: String::from("File is closed")
+        })
+    }
+}
+
+/// Converts error of the ssh2 library to the sftp error
+fn sftp_error(err: ssh2::Error) -> SftpError {
+    let code = match err.code() {
+        ErrorCode::SFTP(code) => Some(code),
+        _ => None
+    };
+
+    SftpError {
+        code,
+        text: err.message().to_string()
+    }
+}
+
+/// Converts io error of the file operation to the sftp error
+fn io_error(err: std::io::Error) -> SftpError {
+    SftpError {
+        code: None,
+        text: err.to_string()
+    }
+}
@@ -0,0 +1,258 @@
+//! Reading of the interactive shell output up to the prompt. Output is read by large blocks and the
+//! prompt is searched only in the new data and the tail window before it, so the time of reading
+//! is linear to the size of the output.
+
+use regex::Regex;
+use regex::bytes;
+use std::io;
+use std::io::prelude::*;
+use std::time::{Duration, Instant};
+
+/// Size of the block read from the shell at once
+pub const READ_BLOCK_SIZE: usize = 32768;
+
+/// Size of the tail of already searched output, which is searched again with the new data. Prompt
+/// which is longer than this window may be missed if it is split between two reads.
+pub const PROMPT_WINDOW: usize = 4096;
+
+/// Reader of the shell output. Data read after the prompt is kept for the next reading.
+pub struct ShellReader {
+
+    /// Data which was read but not yet returned
+    pending: Vec<u8>
+}
+
+impl ShellReader {
+
+    pub fn new() -> ShellReader {
+        ShellReader {
+            pending: Vec::new()
+        }
+    }
+
+    /// Reads the output up to the first match of the prompt. Returns the output before the prompt
+    /// and the matched prompt. If the deadline is specified, the set_timeout is called before each
+    /// read with the remaining time, it must limit the blocking of the reader. When the deadline
+    /// passes, error of the TimedOut kind is returned. If the reading fails, the output read so far
+    /// is kept by the reader and can be taken by take_pending.
+    pub fn read_until<R: Read>(&mut self, reader: &mut R, prompt: &Regex, deadline: Option<Instant>, set_timeout: &mut FnMut(Duration)) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        // Prompt was already compiled as the str regex, so it is valid for bytes too
+        let prompt = bytes::Regex::new(prompt.as_str()).unwrap();
+
+        let mut out = std::mem::replace(&mut self.pending, Vec::new());
+        let mut buf = vec![0; READ_BLOCK_SIZE];
+        let mut searched = 0;
+        loop {
+            let from = searched - std::cmp::min(searched, PROMPT_WINDOW);
+            if let Some(m) = prompt.find(&out[from..]) {
+                let (start, end) = (from + m.start(), from + m.end());
+                self.pending = out.split_off(end);
+                let matched = out.split_off(start);
+                return Ok((out, matched));
+            }
+            searched = out.len();
+
+            if deadline.is_some() {
+                let now = Instant::now();
+                if now >= deadline.unwrap() {
+                    self.pending = out;
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "prompt was not received in time"));
+                }
+                set_timeout(deadline.unwrap() - now);
+            }
+
+            let read = match reader.read(&mut buf) {
+                Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "shell channel is closed")),
+                Ok(n) => Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e)
+            };
+            if read.is_err() {
+                self.pending = out;
+                return Err(read.err().unwrap());
+            }
+            out.extend_from_slice(&buf[..read.unwrap()]);
+        }
+    }
+
+    /// Returns the data which was read but not yet returned
+    pub fn take_pending(&mut self) -> Vec<u8> {
+        std::mem::replace(&mut self.pending, Vec::new())
+    }
+}
+
+/// Decodes the shell output as UTF-8. Invalid sequences are replaced by U+FFFD, or preserved as
+/// \xNN escapes if escape is set.
+pub fn decode_output(out: &[u8], escape: bool) -> String {
+    if !escape {
+        return String::from_utf8_lossy(out).into_owned();
+    }
+
+    let mut text = String::with_capacity(out.len());
+    let mut rest = out;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                break;
+            },
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap());
+                let invalid_len = e.error_len().unwrap_or(invalid.len());
+                for b in &invalid[..invalid_len] {
+                    text.push_str(&format!("\\x{:02x}", b));
+                }
+                rest = &invalid[invalid_len..];
+            }
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Reader which returns the chunks one by one, each read is delayed
+    struct ChunkedReader {
+        chunks: VecDeque<Vec<u8>>,
+        delay: Duration
+    }
+
+    impl ChunkedReader {
+        fn new(chunks: Vec<Vec<u8>>) -> ChunkedReader {
+            ChunkedReader { chunks: chunks.into_iter().collect(), delay: Duration::from_millis(0) }
+        }
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(self.delay);
+            let chunk = self.chunks.pop_front();
+            if chunk.is_none() {
+                return Ok(0);
+            }
+            let mut chunk = chunk.unwrap();
+            let n = std::cmp::min(chunk.len(), buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.chunks.push_front(chunk.split_off(n));
+            }
+            Ok(n)
+        }
+    }
+
+    fn read_until(shell_reader: &mut ShellReader, reader: &mut ChunkedReader, prompt: &str) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        shell_reader.read_until(reader, &Regex::new(prompt).unwrap(), None, &mut |_| ())
+    }
+
+    #[test]
+    fn read_until_prompt_split_between_reads() {
+        let mut first = vec![b'a'; READ_BLOCK_SIZE - 2];
+        first.extend_from_slice(b"tr");
+        let mut second = b"s>rest".to_vec();
+        second.resize(READ_BLOCK_SIZE, b'b');
+        let mut reader = ChunkedReader::new(vec![first, second.clone()]);
+        let mut shell_reader = ShellReader::new();
+
+        let (out, matched) = read_until(&mut shell_reader, &mut reader, "trs>").unwrap();
+        assert_eq!(out, vec![b'a'; READ_BLOCK_SIZE - 2]);
+        assert_eq!(matched, b"trs>");
+        assert_eq!(shell_reader.take_pending(), &second[2..]);
+        assert!(shell_reader.take_pending().is_empty());
+    }
+
+    #[test]
+    fn read_until_match_in_window_tail() {
+        // Prompt starts inside the window and is completed by the next read
+        let mut first = vec![b'a'; READ_BLOCK_SIZE - 100];
+        first.push(b'P');
+        first.resize(READ_BLOCK_SIZE, b'b');
+        let mut reader = ChunkedReader::new(vec![first, b"b>".to_vec()]);
+        let mut shell_reader = ShellReader::new();
+
+        let (out, matched) = read_until(&mut shell_reader, &mut reader, "P[^>]*>").unwrap();
+        assert_eq!(out, vec![b'a'; READ_BLOCK_SIZE - 100]);
+        assert_eq!(matched.len(), 102);
+        assert!(shell_reader.take_pending().is_empty());
+    }
+
+    #[test]
+    fn read_until_prompt_longer_than_window_is_missed() {
+        let mut first = vec![b'P'];
+        first.resize(READ_BLOCK_SIZE, b'b');
+        let mut reader = ChunkedReader::new(vec![first, b"b>".to_vec()]);
+        let mut shell_reader = ShellReader::new();
+
+        let err = read_until(&mut shell_reader, &mut reader, "P[^>]*>").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(shell_reader.take_pending().len(), READ_BLOCK_SIZE + 2);
+    }
+
+    #[test]
+    fn read_until_keeps_data_after_prompt() {
+        let mut reader = ChunkedReader::new(vec![b"one> two> thr".to_vec(), b"ee".to_vec()]);
+        let mut shell_reader = ShellReader::new();
+
+        assert_eq!(read_until(&mut shell_reader, &mut reader, "> ").unwrap(), (b"one".to_vec(), b"> ".to_vec()));
+        // Second prompt is found in the pending data without reading
+        assert_eq!(read_until(&mut shell_reader, &mut reader, "> ").unwrap(), (b"two".to_vec(), b"> ".to_vec()));
+        assert_eq!(reader.chunks.len(), 1);
+
+        let err = read_until(&mut shell_reader, &mut reader, "> ").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(shell_reader.take_pending(), b"three");
+    }
+
+    #[test]
+    fn read_until_deadline() {
+        let mut reader = ChunkedReader::new(vec![b"partial".to_vec(), b" output$ ".to_vec()]);
+        reader.delay = Duration::from_millis(50);
+        let mut shell_reader = ShellReader::new();
+        let mut timeouts = Vec::new();
+
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let result = shell_reader.read_until(&mut reader, &Regex::new("\\$ ").unwrap(), Some(deadline), &mut |t| timeouts.push(t));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(timeouts.len(), 1);
+        assert!(timeouts[0] <= Duration::from_millis(20));
+        assert_eq!(shell_reader.take_pending(), b"partial");
+
+        // Reading may be continued after the timeout
+        let (out, matched) = read_until(&mut shell_reader, &mut reader, "\\$ ").unwrap();
+        assert_eq!(out, b" output");
+        assert_eq!(matched, b"$ ");
+    }
+
+    #[test]
+    fn decode_output_valid_utf8() {
+        let text = "Привет, 世界 🙂";
+        assert_eq!(decode_output(text.as_bytes(), false), text);
+        assert_eq!(decode_output(text.as_bytes(), true), text);
+    }
+
+    #[test]
+    fn decode_output_replaces_invalid_bytes() {
+        assert_eq!(decode_output(b"a\xffb", false), "a\u{fffd}b");
+    }
+
+    #[test]
+    fn decode_output_escapes_invalid_bytes() {
+        assert_eq!(decode_output(b"a\xffb\xfe", true), "a\\xffb\\xfe");
+    }
+
+    #[test]
+    fn decode_output_truncated_sequence_at_end() {
+        // First two bytes of the three-byte '世'
+        assert_eq!(decode_output(b"ok \xe4\xb8", false), "ok \u{fffd}");
+        assert_eq!(decode_output(b"ok \xe4\xb8", true), "ok \\xe4\\xb8");
+    }
+
+    #[test]
+    fn decode_output_truncated_sequence_in_middle() {
+        assert_eq!(decode_output(b"\xe4\xb8a", true), "\\xe4\\xb8a");
+    }
+}
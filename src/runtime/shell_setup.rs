@@ -0,0 +1,413 @@
+//! Setup of the interactive shell prompt. The prompt is replaced by a random sentinel, which is
+//! unique for the session, so the output of commands can't be confused with the prompt. Prompt is
+//! set up in the way of the remote shell family. The prompt also carries the exit status of the
+//! last command, so it is known without running any additional command.
+
+use super::shell_reader::ShellReader;
+use regex::Regex;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::prelude::*;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Pattern of the login prompt, which is used if the script does not specify the prompt. Output
+/// is taken as the prompt if it ends by one of the usual prompt characters of the shells.
+pub const LOGIN_PROMPT: &str = "[$#%>] ?$";
+
+/// Family of the remote shell
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ShellFamily {
+
+    /// sh, bash, ksh, dash, ash and other posix compatible shells
+    Posix,
+
+    /// zsh, it is posix compatible but has its own prompt hooks
+    Zsh,
+
+    /// csh and tcsh
+    Csh,
+
+    /// fish
+    Fish
+}
+
+impl ShellFamily {
+
+    /// Determines the family by the path or name of the shell executable. Unknown shells are
+    /// considered as posix compatible.
+    pub fn from_shell(shell: &str) -> ShellFamily {
+        let name = shell.trim().rsplit('/').next().unwrap_or("").trim_start_matches('-');
+        match name {
+            "zsh" => ShellFamily::Zsh,
+            "csh" | "tcsh" => ShellFamily::Csh,
+            "fish" => ShellFamily::Fish,
+            _ => ShellFamily::Posix
+        }
+    }
+
+    /// Returns the name of the family
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShellFamily::Posix => "posix",
+            ShellFamily::Zsh => "zsh",
+            ShellFamily::Csh => "csh",
+            ShellFamily::Fish => "fish"
+        }
+    }
+
+    /// Returns the command which replaces the prompt by the sentinel followed by the exit status
+    /// of the last command and '>'. Continuation prompt is cleared and prompt hooks, which may
+    /// print to the output or redefine the prompt, are disabled. Sentinel is split by the quotes,
+    /// so the echo of the command does not contain it.
+    pub fn prompt_setup(&self, sentinel: &str) -> String {
+        let quoted = split_quoted(sentinel);
+        match self {
+            ShellFamily::Posix => format!("PS1={}'$?>'; PS2=''; unset PROMPT_COMMAND\n", quoted),
+            ShellFamily::Zsh => format!("PS1={}'%?>'; PS2=''; RPS1=''; precmd_functions=(); unsetopt prompt_sp\n", quoted),
+            ShellFamily::Csh => format!("set prompt={}'%?>'; set prompt2=''; unalias precmd; unalias postcmd\n", quoted),
+            ShellFamily::Fish => format!("function fish_prompt; echo -n {}$status'>'; end; function fish_right_prompt; end; function fish_mode_prompt; end\n", quoted)
+        }
+    }
+}
+
+/// Waits for the login prompt, detects family of the shell and replaces its prompt by the random
+/// sentinel. Each step must complete within the timeout in seconds. The login prompt which did not
+/// appear in time is skipped, the rest of the login output is consumed by the detection. Returns
+/// family of the shell and regex of the new prompt, or text of the error if the sentinel prompt
+/// did not appear. The set_timeout limits the blocking of the shell, see ShellReader::read_until.
+pub fn setup_shell<S: Read + Write>(shell: &mut S, reader: &mut ShellReader, login_prompt: &Regex, timeout: u64, set_timeout: &mut FnMut(Duration)) -> Result<(ShellFamily, Regex), String> {
+    let deadline = || Some(Instant::now() + Duration::from_secs(timeout));
+
+    let _ = reader.read_until(shell, login_prompt, deadline(), set_timeout);
+
+    let (command, pattern) = detect_command();
+    let detect = Regex::new(&pattern).unwrap();
+    write_command(shell, &command)?;
+    let family = match reader.read_until(shell, &detect, deadline(), set_timeout) {
+        Ok((_, matched)) => {
+            let matched = String::from_utf8_lossy(&matched).into_owned();
+            let shell_path = detect.captures(&matched).and_then(|c| c.get(1)).map(|m| m.as_str()).unwrap_or("");
+            ShellFamily::from_shell(shell_path)
+        },
+        Err(_) => ShellFamily::Posix
+    };
+
+    let sentinel = sentinel();
+    let prompt = Regex::new(&prompt_pattern(&sentinel)).unwrap();
+    write_command(shell, &family.prompt_setup(&sentinel))?;
+    if reader.read_until(shell, &prompt, deadline(), set_timeout).is_err() {
+        return Err(format!("Unable to set up prompt of the {} shell: prompt did not appear within {} s", family.name(), timeout));
+    }
+
+    Ok((family, prompt))
+}
+
+/// Writes the command to the shell
+fn write_command<S: Write>(shell: &mut S, command: &str) -> Result<(), String> {
+    let write_result = shell.write_all(command.as_bytes()).and_then(|_| shell.flush());
+    if write_result.is_err() {
+        return Err(format!("Unable to write to the shell: {}", write_result.err().unwrap()));
+    }
+
+    Ok(())
+}
+
+/// Returns the random sentinel of the session
+pub fn sentinel() -> String {
+    format!("trs-{}-", random_token())
+}
+
+/// Returns the regex of the prompt set up by prompt_setup, exit status is in the first group. The
+/// group is not restricted to digits, because shells which can't show the status in the prompt
+/// (plain csh) print the escape as is.
+pub fn prompt_pattern(sentinel: &str) -> String {
+    format!("{}([^>\\s]*)>", regex::escape(sentinel))
+}
+
+/// Returns the exit status from the prompt matched by the prompt_pattern regex
+pub fn prompt_status(prompt: &Regex, matched: &[u8]) -> Option<i32> {
+    let matched = String::from_utf8_lossy(matched);
+    prompt.captures(&matched).and_then(|c| c.get(1)).and_then(|m| m.as_str().parse::<i32>().ok())
+}
+
+/// Returns the command which prints the marker and the login shell of the user, and the regex
+/// which matches its output with the shell in the first group. The command works in all supported
+/// shells, the marker is split by quotes, so the echo of the command does not match the regex.
+/// $SHELL is the login shell of the user, not the running one. If the profile starts another
+/// shell (for example by exec zsh), the family is detected wrong and the prompt setup may fail.
+pub fn detect_command() -> (String, String) {
+    let marker = format!("trs-shell-{}", random_token());
+    let command = format!("echo {} $SHELL\n", split_quoted(&marker));
+    let pattern = format!("{} ([^\\s]*)\\r?\\n", regex::escape(&marker));
+
+    (command, pattern)
+}
+
+/// Returns the text as two adjacent single quoted parts, all supported shells concatenate them
+fn split_quoted(text: &str) -> String {
+    let middle = text.len() / 2;
+    format!("'{}''{}'", &text[..middle], &text[middle..])
+}
+
+/// Returns random hex token. Hasher of the RandomState is seeded by the random keys, which are
+/// different for each instance.
+fn random_token() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    hasher.write_u128(nanos);
+
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// Shell behind the pty, which answers to the commands of setup_shell. Reading times out when
+    /// there is no output.
+    struct FakeShell {
+
+        /// Login shell of the user ($SHELL)
+        login_shell: &'static str,
+
+        /// Current prompt
+        prompt: String,
+
+        /// Status escape is shown as is, like plain csh does
+        no_status: bool,
+
+        /// Prompt can't be changed, like in the restricted shells
+        restricted: bool,
+
+        input: Vec<u8>,
+        output: Vec<u8>,
+
+        /// Received commands
+        commands: Vec<String>
+    }
+
+    impl FakeShell {
+        fn new(login_shell: &'static str, motd: &str, prompt: &str) -> FakeShell {
+            FakeShell {
+                login_shell,
+                prompt: String::from(prompt),
+                no_status: false,
+                restricted: false,
+                input: Vec::new(),
+                output: format!("{}{}", motd, prompt).into_bytes(),
+                commands: Vec::new()
+            }
+        }
+
+        fn command(&mut self, line: &str) {
+            // Echo of the pty
+            self.output.extend_from_slice(format!("{}\r\n", line).as_bytes());
+            self.commands.push(String::from(line));
+
+            let unquoted = line.replace('\'', "");
+            let sentinel = Regex::new("trs-[0-9a-f]{16}-").unwrap();
+            if line.starts_with("echo ") && line.ends_with(" $SHELL") {
+                let marker = &unquoted[5..unquoted.len() - 7];
+                self.output.extend_from_slice(format!("{} {}\r\n", marker, self.login_shell).as_bytes());
+            } else if let Some(m) = sentinel.find(&unquoted) {
+                if !self.restricted {
+                    self.prompt = format!("{}{}>", m.as_str(), if self.no_status { "%?" } else { "0" });
+                }
+            }
+            self.output.extend_from_slice(self.prompt.as_bytes());
+        }
+    }
+
+    impl Read for FakeShell {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.output.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no output"));
+            }
+            let n = std::cmp::min(buf.len(), self.output.len());
+            buf[..n].copy_from_slice(&self.output[..n]);
+            self.output.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for FakeShell {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.input.extend_from_slice(buf);
+            while let Some(i) = self.input.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.input.drain(..=i).collect();
+                self.command(String::from_utf8_lossy(&line).trim_end());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn setup(shell: &mut FakeShell, reader: &mut ShellReader, login_prompt: &str) -> Result<(ShellFamily, Regex), String> {
+        setup_shell(shell, reader, &Regex::new(login_prompt).unwrap(), 1, &mut |_| ())
+    }
+
+    /// Executes the command in the shell set up with the prompt, returns its status
+    fn exec_status(shell: &mut FakeShell, reader: &mut ShellReader, prompt: &Regex) -> Option<i32> {
+        shell.write_all(b"true\n").unwrap();
+        let (out, matched) = reader.read_until(shell, prompt, None, &mut |_| ()).unwrap();
+        assert_eq!(out, b"true\r\n");
+        prompt_status(prompt, &matched)
+    }
+
+    #[test]
+    fn login_prompt_pattern() {
+        let login_prompt = Regex::new(LOGIN_PROMPT).unwrap();
+        for prompt in &["user@host:~$ ", "root@host:~# ", "host% ", "user@host ~> ", "[user@host]$"] {
+            assert!(login_prompt.is_match(&format!("Last login: Mon Oct 12\r\n{}", prompt)), "{}", prompt);
+        }
+        assert!(!login_prompt.is_match("Last login: Mon Oct 12\r\n"));
+        assert!(!login_prompt.is_match("$ ls\r\n"));
+    }
+
+    #[test]
+    fn setup_shell_fish() {
+        let mut shell = FakeShell::new("/usr/bin/fish", "Welcome to fish, the friendly interactive shell\r\n", "user@host ~> ");
+        let mut reader = ShellReader::new();
+
+        let (family, prompt) = setup(&mut shell, &mut reader, LOGIN_PROMPT).unwrap();
+        assert_eq!(family, ShellFamily::Fish);
+        assert_eq!(shell.commands.len(), 2);
+        assert!(shell.commands[1].starts_with("function fish_prompt;"), "{}", shell.commands[1]);
+        assert!(reader.take_pending().is_empty());
+        assert_eq!(exec_status(&mut shell, &mut reader, &prompt), Some(0));
+    }
+
+    #[test]
+    fn setup_shell_tcsh() {
+        let mut shell = FakeShell::new("/bin/tcsh", "", "host% ");
+        let mut reader = ShellReader::new();
+
+        let (family, prompt) = setup(&mut shell, &mut reader, LOGIN_PROMPT).unwrap();
+        assert_eq!(family, ShellFamily::Csh);
+        assert!(shell.commands[1].starts_with("set prompt="), "{}", shell.commands[1]);
+        assert_eq!(exec_status(&mut shell, &mut reader, &prompt), Some(0));
+    }
+
+    #[test]
+    fn setup_shell_plain_csh_has_no_status() {
+        let mut shell = FakeShell::new("/bin/csh", "", "% ");
+        shell.no_status = true;
+        let mut reader = ShellReader::new();
+
+        let (family, prompt) = setup(&mut shell, &mut reader, LOGIN_PROMPT).unwrap();
+        assert_eq!(family, ShellFamily::Csh);
+        assert_eq!(exec_status(&mut shell, &mut reader, &prompt), None);
+    }
+
+    #[test]
+    fn setup_shell_custom_login_prompt() {
+        // Sentinel is set up after the custom prompt too
+        let mut shell = FakeShell::new("/bin/sh", "", "router: ");
+        let mut reader = ShellReader::new();
+
+        let (family, prompt) = setup(&mut shell, &mut reader, "router: ").unwrap();
+        assert_eq!(family, ShellFamily::Posix);
+        assert!(shell.commands[1].starts_with("PS1="), "{}", shell.commands[1]);
+        assert_eq!(exec_status(&mut shell, &mut reader, &prompt), Some(0));
+    }
+
+    #[test]
+    fn setup_shell_unknown_login_prompt() {
+        // Login prompt is not recognized, the shell is set up after the timeout
+        let mut shell = FakeShell::new("/bin/bash", "", "router: ");
+        let mut reader = ShellReader::new();
+
+        let (family, prompt) = setup(&mut shell, &mut reader, LOGIN_PROMPT).unwrap();
+        assert_eq!(family, ShellFamily::Posix);
+        assert_eq!(exec_status(&mut shell, &mut reader, &prompt), Some(0));
+    }
+
+    #[test]
+    fn setup_shell_restricted() {
+        let mut shell = FakeShell::new("/bin/rbash", "", "$ ");
+        shell.restricted = true;
+        let mut reader = ShellReader::new();
+
+        let err = setup(&mut shell, &mut reader, LOGIN_PROMPT).unwrap_err();
+        assert_eq!(err, "Unable to set up prompt of the posix shell: prompt did not appear within 1 s");
+    }
+
+    #[test]
+    fn from_shell_by_path_or_name() {
+        assert_eq!(ShellFamily::from_shell("/bin/bash"), ShellFamily::Posix);
+        assert_eq!(ShellFamily::from_shell("/usr/bin/zsh\r"), ShellFamily::Zsh);
+        assert_eq!(ShellFamily::from_shell("-tcsh"), ShellFamily::Csh);
+        assert_eq!(ShellFamily::from_shell("/bin/csh"), ShellFamily::Csh);
+        assert_eq!(ShellFamily::from_shell("/usr/local/bin/fish"), ShellFamily::Fish);
+    }
+
+    #[test]
+    fn from_shell_unknown_is_posix() {
+        assert_eq!(ShellFamily::from_shell(""), ShellFamily::Posix);
+        assert_eq!(ShellFamily::from_shell("/usr/sbin/nologin"), ShellFamily::Posix);
+        assert_eq!(ShellFamily::from_shell("/bin/zsh5"), ShellFamily::Posix);
+    }
+
+    #[test]
+    fn prompt_setup_does_not_contain_sentinel() {
+        let sentinel = sentinel();
+        for family in &[ShellFamily::Posix, ShellFamily::Zsh, ShellFamily::Csh, ShellFamily::Fish] {
+            let setup = family.prompt_setup(&sentinel);
+            assert!(!setup.contains(&sentinel), "{}", setup);
+            assert!(setup.ends_with('\n'));
+        }
+    }
+
+    #[test]
+    fn prompt_status_numeric() {
+        let sentinel = sentinel();
+        let prompt = Regex::new(&prompt_pattern(&sentinel)).unwrap();
+        assert_eq!(prompt_status(&prompt, format!("{}0>", sentinel).as_bytes()), Some(0));
+        assert_eq!(prompt_status(&prompt, format!("{}127>", sentinel).as_bytes()), Some(127));
+    }
+
+    #[test]
+    fn prompt_status_not_numeric() {
+        // Plain csh prints the %? escape as is, fish may print nothing
+        let sentinel = sentinel();
+        let prompt = Regex::new(&prompt_pattern(&sentinel)).unwrap();
+        assert!(prompt.is_match(&format!("{}%?>", sentinel)));
+        assert_eq!(prompt_status(&prompt, format!("{}%?>", sentinel).as_bytes()), None);
+        assert_eq!(prompt_status(&prompt, format!("{}>", sentinel).as_bytes()), None);
+    }
+
+    #[test]
+    fn prompt_pattern_escapes_sentinel() {
+        let prompt = Regex::new(&prompt_pattern("a.b-")).unwrap();
+        assert!(prompt.is_match("a.b-0>"));
+        assert!(!prompt.is_match("axb-0>"));
+    }
+
+    #[test]
+    fn detect_command_echo_does_not_match() {
+        let (command, pattern) = detect_command();
+        let detect = Regex::new(&pattern).unwrap();
+        assert!(!detect.is_match(&command));
+
+        // Output of the command is the joined marker and the shell
+        let marker = command.trim_start_matches("echo ").split(" $SHELL").next().unwrap().replace('\'', "");
+        let out = format!("{}\r\n{} /bin/zsh\r\n", command.trim_end(), marker);
+        let shell = detect.captures(&out).and_then(|c| c.get(1)).map(|m| m.as_str());
+        assert_eq!(shell, Some("/bin/zsh"));
+    }
+
+    #[test]
+    fn split_quoted_halves() {
+        assert_eq!(split_quoted("abcd"), "'ab''cd'");
+        assert_eq!(split_quoted("abc"), "'a''bc'");
+    }
+
+    #[test]
+    fn sentinels_are_unique() {
+        assert_ne!(sentinel(), sentinel());
+    }
+}
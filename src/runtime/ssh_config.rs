@@ -0,0 +1,496 @@
+//! Parser of the OpenSSH client configuration file (~/.ssh/config). Only options which affect the
+//! connection establishing are supported, all others are silently ignored.
+
+use super::connection::DEFAULT_SSH_PORT;
+use std::fs;
+use std::env;
+
+/// Options of the specific host collected from the config file
+#[derive(Default, Debug)]
+pub struct HostConfig {
+
+    /// Real host name to connect to
+    pub host_name: Option<String>,
+
+    /// Port of the ssh server
+    pub port: Option<u16>,
+
+    /// Remote user name
+    pub user: Option<String>,
+
+    /// Private key files in order of declaration
+    pub identity_files: Vec<String>,
+
+    /// Jump hosts chain in form [user@]host[:port][,[user@]host[:port]...]
+    pub proxy_jump: Option<String>,
+
+    /// Command which stdin/stdout is used as connection to the host. Tokens of the command are
+    /// not expanded, because the final port and user are not known at config resolving.
+    pub proxy_command: Option<String>,
+
+    /// Timeout of the tcp connection establishing in seconds
+    pub connect_timeout: Option<u64>,
+
+    /// Interval of the keepalive messages in seconds
+    pub server_alive_interval: Option<u32>
+}
+
+/// Condition of the config block
+enum Condition {
+
+    /// Options before the first Host or Match keyword, they are applied to all hosts
+    Always,
+
+    /// Host block with list of patterns
+    Host(Vec<String>),
+
+    /// Match block with list of criteria and them arguments
+    Match(Vec<(String, Option<String>)>)
+}
+
+/// Block of options with condition under which they are applied
+struct Block {
+    condition: Condition,
+    options: Vec<(String, Vec<String>)>
+}
+
+/// Criteria of the Match keyword which are supported, blocks with other criteria are never applied
+const MATCH_CRITERIA: [&str; 5] = ["all", "host", "originalhost", "user", "localuser"];
+
+/// Parsed ssh config file
+pub struct SshConfig {
+    blocks: Vec<Block>,
+
+    /// Problems of the config which does not prevent its using
+    warnings: Vec<String>
+}
+
+impl SshConfig {
+
+    /// Creates empty config which does not affect any host
+    pub fn empty() -> SshConfig {
+        SshConfig {
+            blocks: Vec::new(),
+            warnings: Vec::new()
+        }
+    }
+
+    /// Returns problems of the config which does not prevent its using, such as unsupported Match
+    /// criteria
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Returns path of the default user config file
+    pub fn default_path() -> String {
+        format!("{}/.ssh/config", home_dir())
+    }
+
+    /// Loads and parses config file. Returns text of the error if file can't be read or has
+    /// incorrect syntax.
+    pub fn load(path: &str) -> Result<SshConfig, String> {
+        let content = fs::read_to_string(path);
+        if content.is_err() {
+            return Err(format!("Unable to read ssh config file '{}': {}", path, content.err().unwrap()));
+        }
+
+        let config = Self::parse(&content.unwrap());
+        if config.is_err() {
+            return Err(format!("Invalid ssh config file '{}': {}", path, config.err().unwrap()));
+        }
+        let mut config = config.unwrap();
+        config.warnings = config.warnings.iter().map(|w| format!("ssh config file '{}': {}", path, w)).collect();
+
+        Ok(config)
+    }
+
+    /// Parses config text
+    pub fn parse(content: &str) -> Result<SshConfig, String> {
+        let mut blocks = vec![Block { condition: Condition::Always, options: Vec::new() }];
+        let mut warnings = Vec::new();
+
+        for (i, line) in content.lines().enumerate() {
+            let line_n = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, mut args) = split_line(line);
+            if args.is_empty() {
+                return Err(format!("line {}: keyword '{}' has no arguments", line_n, keyword));
+            }
+
+            match &keyword[..] {
+                "host" => {
+                    blocks.push(Block { condition: Condition::Host(args), options: Vec::new() });
+                },
+                "match" => {
+                    let mut criteria = Vec::new();
+                    let mut args = args.into_iter();
+                    while let Some(criterion) = args.next() {
+                        let criterion = criterion.to_lowercase();
+                        if criterion == "all" || criterion == "canonical" || criterion == "final" {
+                            criteria.push((criterion, None));
+                        } else {
+                            let arg = args.next();
+                            if arg.is_none() {
+                                return Err(format!("line {}: match criterion '{}' has no argument", line_n, criterion));
+                            }
+                            criteria.push((criterion, arg));
+                        }
+                    }
+                    for (criterion, _) in criteria.iter().filter(|(c, _)| !MATCH_CRITERIA.contains(&&c[..])) {
+                        warnings.push(format!("line {}: match criterion '{}' is not supported, the block is never applied", line_n, criterion));
+                    }
+                    blocks.push(Block { condition: Condition::Match(criteria), options: Vec::new() });
+                },
+                "proxycommand" => {
+                    // Command is passed to the shell as is, so it is not split to arguments
+                    args = vec![String::from(split_keyword(line).1)];
+                    blocks.last_mut().unwrap().options.push((keyword, args));
+                },
+                "port" => {
+                    let port = args[0].parse::<u16>();
+                    if port.is_err() || port.unwrap() == 0 {
+                        return Err(format!("line {}: bad port number '{}'", line_n, args[0]));
+                    }
+                    blocks.last_mut().unwrap().options.push((keyword, args));
+                },
+                "connecttimeout" | "serveraliveinterval" => {
+                    if args[0].parse::<u32>().is_err() {
+                        return Err(format!("line {}: bad number '{}' for '{}'", line_n, args[0], keyword));
+                    }
+                    blocks.last_mut().unwrap().options.push((keyword, args));
+                },
+                _ => {
+                    blocks.last_mut().unwrap().options.push((keyword, args));
+                }
+            }
+        }
+
+        Ok(SshConfig { blocks, warnings })
+    }
+
+    /// Collects options of the host. Host is the name which is used by the script, it may be an
+    /// alias from the config. As in the OpenSSH, the first obtained value of each option is used,
+    /// except IdentityFile, values of which are accumulated.
+    pub fn resolve(&self, host: &str) -> HostConfig {
+        let local_user = local_user();
+        let mut config = HostConfig::default();
+
+        for block in &self.blocks {
+            let matched = {
+                let host_name = config.host_name.as_ref().map(|h| &h[..]).unwrap_or(host);
+                let user = config.user.as_ref().map(|u| &u[..]).unwrap_or(&local_user[..]);
+                match &block.condition {
+                    Condition::Always => true,
+                    Condition::Host(patterns) => match_pattern_list(patterns, host),
+                    Condition::Match(criteria) => {
+                        criteria.iter().all(|(criterion, arg)| {
+                            let patterns: Vec<String> = arg.as_ref()
+                                .map(|a| a.split(',').map(String::from).collect())
+                                .unwrap_or(Vec::new());
+                            match &criterion[..] {
+                                "all" => true,
+                                "host" => match_pattern_list(&patterns, host_name),
+                                "originalhost" => match_pattern_list(&patterns, host),
+                                "user" => match_pattern_list(&patterns, user),
+                                "localuser" => match_pattern_list(&patterns, &local_user),
+                                _ => false
+                            }
+                        })
+                    }
+                }
+            };
+
+            if matched {
+                for (keyword, args) in &block.options {
+                    apply_option(&mut config, keyword, args);
+                }
+            }
+        }
+
+        // Expand tokens
+        if config.host_name.is_some() {
+            config.host_name = Some(expand_tokens(config.host_name.as_ref().unwrap(), host, host, "", ""));
+        }
+        let host_name = config.host_name.clone().unwrap_or(String::from(host));
+        let user = config.user.clone().unwrap_or(local_user);
+        let port = config.port.unwrap_or(DEFAULT_SSH_PORT).to_string();
+        config.identity_files = config.identity_files.iter()
+            .map(|f| expand_tokens(f, host, &host_name, &port, &user))
+            .collect();
+
+        config
+    }
+}
+
+/// Sets option value to the config if it was not set early
+fn apply_option(config: &mut HostConfig, keyword: &str, args: &[String]) {
+    let value = &args[0];
+    match keyword {
+        "hostname" => {
+            if config.host_name.is_none() {
+                config.host_name = Some(value.clone());
+            }
+        },
+        "port" => {
+            if config.port.is_none() {
+                config.port = value.parse().ok();
+            }
+        },
+        "user" => {
+            if config.user.is_none() {
+                config.user = Some(value.clone());
+            }
+        },
+        "identityfile" => {
+            config.identity_files.push(value.clone());
+        },
+        "proxyjump" => {
+            // As in the OpenSSH, ProxyJump and ProxyCommand override each other, the first one wins
+            if config.proxy_jump.is_none() && config.proxy_command.is_none() {
+                config.proxy_jump = Some(value.clone());
+            }
+        },
+        "proxycommand" => {
+            if config.proxy_jump.is_none() && config.proxy_command.is_none() {
+                config.proxy_command = Some(value.clone());
+            }
+        },
+        "connecttimeout" => {
+            if config.connect_timeout.is_none() {
+                config.connect_timeout = value.parse().ok();
+            }
+        },
+        "serveraliveinterval" => {
+            if config.server_alive_interval.is_none() {
+                config.server_alive_interval = value.parse().ok();
+            }
+        },
+        _ => {}
+    }
+}
+
+/// Splits config line to the lowercase keyword and list of arguments. Keyword may be separated from
+/// arguments by whitespaces or by the '=' char. Arguments may be quoted.
+fn split_line(line: &str) -> (String, Vec<String>) {
+    let (keyword, rest) = split_keyword(line);
+
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut has_arg = false;
+    for c in rest.chars() {
+        if c == '"' {
+            quoted = !quoted;
+            has_arg = true;
+        } else if c.is_whitespace() && !quoted {
+            if has_arg {
+                args.push(current.clone());
+                current.clear();
+                has_arg = false;
+            }
+        } else {
+            current.push(c);
+            has_arg = true;
+        }
+    }
+    if has_arg {
+        args.push(current);
+    }
+
+    (keyword, args)
+}
+
+/// Splits config line to the lowercase keyword and the rest of the line
+fn split_keyword(line: &str) -> (String, &str) {
+    let sep = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let keyword = line[..sep].to_lowercase();
+    let rest = line[sep..].trim_start();
+    let rest = if rest.starts_with('=') {
+        rest[1..].trim_start()
+    } else {
+        rest
+    };
+
+    (keyword, rest)
+}
+
+/// Checks value against the list of patterns. Value matches if it matches at least one of
+/// positive patterns and does not match any of negated ('!pattern') patterns.
+fn match_pattern_list(patterns: &[String], value: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        if pattern.starts_with('!') {
+            if match_pattern(&pattern[1..], value) {
+                return false;
+            }
+        } else if match_pattern(pattern, value) {
+            matched = true;
+        }
+    }
+
+    matched
+}
+
+/// Matches value with the pattern, where '*' matches any sequence of chars and '?' matches any
+/// single char. Matching is case insensitive.
+fn match_pattern(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let value: Vec<char> = value.to_lowercase().chars().collect();
+
+    fn do_match(p: &[char], v: &[char]) -> bool {
+        if p.is_empty() {
+            return v.is_empty();
+        }
+        match p[0] {
+            '*' => (0..=v.len()).any(|i| do_match(&p[1..], &v[i..])),
+            '?' => !v.is_empty() && do_match(&p[1..], &v[1..]),
+            c => !v.is_empty() && v[0] == c && do_match(&p[1..], &v[1..])
+        }
+    }
+
+    do_match(&pattern, &value)
+}
+
+/// Expands '~' at the start of value and OpenSSH tokens - %% (literal '%'), %d (home directory),
+/// %h (remote host name), %n (original host name), %p (remote port), %r (remote user) and %u (local
+/// user)
+pub fn expand_tokens(value: &str, original_host: &str, host_name: &str, port: &str, user: &str) -> String {
+    let value = if value.starts_with("~/") {
+        format!("{}{}", home_dir(), &value[1..])
+    } else {
+        String::from(value)
+    };
+
+    let mut result = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => result.push('%'),
+            Some('d') => result.push_str(&home_dir()),
+            Some('h') => result.push_str(host_name),
+            Some('n') => result.push_str(original_host),
+            Some('p') => result.push_str(port),
+            Some('r') => result.push_str(user),
+            Some('u') => result.push_str(&local_user()),
+            Some(other) => {
+                result.push('%');
+                result.push(other);
+            },
+            None => result.push('%')
+        }
+    }
+
+    result
+}
+
+/// Returns home directory of the local user
+fn home_dir() -> String {
+    env::var("HOME").unwrap_or(String::from("."))
+}
+
+/// Returns name of the local user
+pub fn local_user() -> String {
+    env::var("USER").or(env::var("LOGNAME")).unwrap_or(String::from("root"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_keyword_separators_and_quotes() {
+        let config = SshConfig::parse("Host web\n  HostName=10.0.0.1\n  User \"deploy user\"\n  # comment\n\n  PORT 2222\n").unwrap();
+        let host = config.resolve("web");
+        assert_eq!(host.host_name, Some(String::from("10.0.0.1")));
+        assert_eq!(host.user, Some(String::from("deploy user")));
+        assert_eq!(host.port, Some(2222));
+    }
+
+    #[test]
+    fn parse_bad_port() {
+        assert!(SshConfig::parse("Host web\n  Port 70000\n").err().unwrap().starts_with("line 2:"));
+        assert!(SshConfig::parse("Port 0\n").is_err());
+        assert!(SshConfig::parse("Port ssh\n").is_err());
+    }
+
+    #[test]
+    fn parse_missing_arguments() {
+        assert!(SshConfig::parse("Host\n").err().unwrap().starts_with("line 1:"));
+        assert!(SshConfig::parse("Match host\n").is_err());
+    }
+
+    #[test]
+    fn parse_unsupported_match_criterion_warning() {
+        let config = SshConfig::parse("Match exec \"true\" host web\n  User deploy\nMatch all\n  Port 22\n").unwrap();
+        assert_eq!(config.warnings().len(), 1);
+        assert!(config.warnings()[0].starts_with("line 1: match criterion 'exec'"));
+        assert_eq!(config.resolve("web").user, None);
+    }
+
+    #[test]
+    fn proxy_command_is_not_split() {
+        let config = SshConfig::parse("ProxyCommand ssh -W %h:%p  \"bastion\"\n").unwrap();
+        assert_eq!(config.resolve("web").proxy_command, Some(String::from("ssh -W %h:%p  \"bastion\"")));
+    }
+
+    #[test]
+    fn resolve_first_value_wins() {
+        let config = SshConfig::parse("Host web\n  User first\n  IdentityFile /a\nHost *\n  User second\n  IdentityFile /b\n").unwrap();
+        let host = config.resolve("web");
+        assert_eq!(host.user, Some(String::from("first")));
+        assert_eq!(host.identity_files, vec![String::from("/a"), String::from("/b")]);
+        assert_eq!(config.resolve("db").user, Some(String::from("second")));
+    }
+
+    #[test]
+    fn resolve_host_patterns() {
+        let config = SshConfig::parse("Host *.internal !secret.internal\n  User deploy\nHost db?\n  Port 2222\n").unwrap();
+        assert_eq!(config.resolve("WEB.internal").user, Some(String::from("deploy")));
+        assert_eq!(config.resolve("secret.internal").user, None);
+        assert_eq!(config.resolve("db1").port, Some(2222));
+        assert_eq!(config.resolve("db10").port, None);
+    }
+
+    #[test]
+    fn resolve_match_host_uses_host_name() {
+        let config = SshConfig::parse("Host web\n  HostName 10.0.0.1\nMatch host 10.0.0.* originalhost web\n  Port 2222\n").unwrap();
+        assert_eq!(config.resolve("web").port, Some(2222));
+    }
+
+    #[test]
+    fn resolve_proxy_jump_and_command_exclude_each_other() {
+        let config = SshConfig::parse("Host web\n  ProxyCommand nc %h %p\nHost *\n  ProxyJump bastion\n").unwrap();
+        let host = config.resolve("web");
+        assert_eq!(host.proxy_command, Some(String::from("nc %h %p")));
+        assert_eq!(host.proxy_jump, None);
+    }
+
+    #[test]
+    fn resolve_expands_identity_file_tokens() {
+        let config = SshConfig::parse("Host web\n  HostName %h.internal\n  User deploy\n  Port 2222\n  IdentityFile /keys/%n-%h-%p-%r\n").unwrap();
+        let host = config.resolve("web");
+        assert_eq!(host.host_name, Some(String::from("web.internal")));
+        assert_eq!(host.identity_files, vec![String::from("/keys/web-web.internal-2222-deploy")]);
+    }
+
+    #[test]
+    fn expand_tokens_literal_percent_and_unknown() {
+        assert_eq!(expand_tokens("%%h %h %x 100%", "orig", "host", "22", "user"), "%h host %x 100%");
+        assert_eq!(expand_tokens("%n:%p:%r", "orig", "host", "22", "user"), "orig:22:user");
+    }
+
+    #[test]
+    fn expand_tokens_home() {
+        assert_eq!(expand_tokens("~/.ssh/id_%h", "orig", "host", "22", "user"), format!("{}/.ssh/id_host", home_dir()));
+        assert_eq!(expand_tokens("%d/key", "orig", "host", "22", "user"), format!("{}/key", home_dir()));
+        assert_eq!(expand_tokens("/a~/b", "orig", "host", "22", "user"), "/a~/b");
+    }
+}
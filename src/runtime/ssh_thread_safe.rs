@@ -2,13 +2,13 @@
 
 use ssh2::{Session, Channel};
 
-pub struct ThreadSafeChannel<'a> {
-    pub channel: Channel<'a>
+pub struct ThreadSafeChannel {
+    pub channel: Channel
 }
 
-unsafe impl <'a> Send for ThreadSafeChannel<'a> {}
-unsafe impl <'a> Sync for ThreadSafeChannel<'a> {}
-impl <'a> Clone for ThreadSafeChannel<'a> {
+unsafe impl Send for ThreadSafeChannel {}
+unsafe impl Sync for ThreadSafeChannel {}
+impl Clone for ThreadSafeChannel {
     fn clone(&self) -> Self {
         unimplemented!()
     }
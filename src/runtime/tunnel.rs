@@ -0,0 +1,321 @@
+//! Tunnels between ssh channels and local streams. Tunnels are served by the connection thread,
+//! which periodically pumps data through them while the session is in the non-blocking mode.
+
+use ssh2::{Session, Channel, Listener};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{TcpStream, TcpListener};
+use std::os::unix::net::UnixStream;
+
+/// Size of the buffer used for single read operation of the tunnel
+const TUNNEL_BUF_SIZE: usize = 32768;
+
+/// Local end of the tunnel
+pub trait TunnelStream: Read + Write + Send {}
+
+impl TunnelStream for TcpStream {}
+impl TunnelStream for UnixStream {}
+
+/// Bidirectional pipe between ssh channel and a local non-blocking stream
+pub struct Tunnel {
+
+    /// Remote end of the tunnel
+    channel: Channel,
+
+    /// Local end of the tunnel
+    stream: Box<TunnelStream>,
+
+    /// Data read from the stream and not yet written to the channel
+    to_channel: Vec<u8>,
+
+    /// Data read from the channel and not yet written to the stream
+    to_stream: Vec<u8>,
+
+    /// Local stream was closed
+    stream_eof: bool,
+
+    /// Remote channel was closed
+    channel_eof: bool,
+
+    /// Eof was sent to the channel
+    eof_sent: bool
+}
+
+impl Tunnel {
+
+    /// Creates tunnel between the channel and the stream. Stream must be in the non-blocking mode.
+    pub fn new(channel: Channel, stream: Box<TunnelStream>) -> Tunnel {
+        Tunnel {
+            channel,
+            stream,
+            to_channel: Vec::new(),
+            to_stream: Vec::new(),
+            stream_eof: false,
+            channel_eof: false,
+            eof_sent: false
+        }
+    }
+
+    /// Moves all immediately available data in both directions. Session of the channel must be in
+    /// the non-blocking mode. Returns count of the transferred bytes, or None if the tunnel was
+    /// closed by any side or broken and must be dropped.
+    pub fn pump(&mut self) -> Option<usize> {
+        let mut moved = 0;
+        let mut buf = [0; TUNNEL_BUF_SIZE];
+
+        // Local stream -> remote channel
+        if !self.stream_eof && self.to_channel.is_empty() {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.stream_eof = true,
+                Ok(n) => self.to_channel.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(_) => return None
+            }
+        }
+        if !self.to_channel.is_empty() {
+            match self.channel.write(&self.to_channel) {
+                Ok(n) => {
+                    self.to_channel.drain(..n);
+                    moved += n;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(_) => return None
+            }
+        }
+        if self.stream_eof && self.to_channel.is_empty() && !self.eof_sent {
+            if self.channel.send_eof().is_ok() {
+                self.eof_sent = true;
+            }
+        }
+
+        // Remote channel -> local stream
+        if !self.channel_eof && self.to_stream.is_empty() {
+            match self.channel.read(&mut buf) {
+                Ok(0) => {
+                    if self.channel.eof() {
+                        self.channel_eof = true;
+                    }
+                },
+                Ok(n) => self.to_stream.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(_) => return None
+            }
+        }
+        if !self.to_stream.is_empty() {
+            match self.stream.write(&self.to_stream) {
+                Ok(n) => {
+                    self.to_stream.drain(..n);
+                    moved += n;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(_) => return None
+            }
+        }
+
+        if self.channel_eof && self.to_stream.is_empty() {
+            return None;
+        }
+
+        Some(moved)
+    }
+}
+
+/// Port forwarding served by the connection thread
+pub trait Forward {
+
+    /// Returns identifier of the forwarding inside of the connection
+    fn id(&self) -> usize;
+
+    /// Returns human readable description of the forwarding
+    fn describe(&self) -> String;
+
+    /// Accepts all pending connections and creates tunnels for them. Session must be in the
+    /// blocking mode. Returns true if some connection was accepted.
+    fn accept(&mut self, session: &Session) -> bool;
+
+    /// Returns tunnels of the accepted connections
+    fn tunnels(&mut self) -> &mut Vec<Tunnel>;
+}
+
+/// Local port forwarding. Each connection accepted by the local listener is tunneled to the remote
+/// host through the new direct-tcpip channel.
+pub struct LocalForward {
+
+    /// Identifier of the forwarding inside of the connection
+    id: usize,
+
+    /// Non-blocking listener of the local port
+    listener: TcpListener,
+
+    /// Listened local port
+    local_port: u16,
+
+    /// Remote host to which connections are forwarded
+    host: String,
+
+    /// Remote port to which connections are forwarded
+    port: u16,
+
+    /// Tunnels of the accepted connections
+    tunnels: Vec<Tunnel>
+}
+
+impl LocalForward {
+
+    /// Binds the listener to the local port on the loopback interface. If port is 0, any free port
+    /// is used. Returns the forwarding and the bound port.
+    pub fn bind(id: usize, local_port: u16, host: String, port: u16) -> Result<(LocalForward, u16), String> {
+        let listener = TcpListener::bind(("127.0.0.1", local_port));
+        if listener.is_err() {
+            return Err(format!("Unable to listen local port {}: {}", local_port, listener.err().unwrap()));
+        }
+        let listener = listener.unwrap();
+
+        let bound_port = listener.local_addr().map(|a| a.port());
+        if bound_port.is_err() {
+            return Err(format!("Unable to listen local port {}: {}", local_port, bound_port.err().unwrap()));
+        }
+        let nonblocking_result = listener.set_nonblocking(true);
+        if nonblocking_result.is_err() {
+            return Err(format!("Unable to listen local port {}: {}", local_port, nonblocking_result.err().unwrap()));
+        }
+
+        let bound_port = bound_port.unwrap();
+        let forward = LocalForward {
+            id,
+            listener,
+            local_port: bound_port,
+            host,
+            port,
+            tunnels: Vec::new()
+        };
+
+        Ok((forward, bound_port))
+    }
+}
+
+impl Forward for LocalForward {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn describe(&self) -> String {
+        format!("L 127.0.0.1:{} -> {}:{}", self.local_port, self.host, self.port)
+    }
+
+    /// Connections for which channel can't be opened are closed
+    fn accept(&mut self, session: &Session) -> bool {
+        let mut accepted = false;
+
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(r) => r,
+                Err(_) => break
+            };
+            accepted = true;
+
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let peer = (&peer.ip().to_string()[..], peer.port());
+            let channel = session.channel_direct_tcpip(&self.host, self.port, Some(peer));
+            if channel.is_ok() {
+                self.tunnels.push(Tunnel::new(channel.unwrap(), Box::new(stream)));
+            }
+        }
+
+        accepted
+    }
+
+    fn tunnels(&mut self) -> &mut Vec<Tunnel> {
+        &mut self.tunnels
+    }
+}
+
+/// Remote port forwarding. The remote server listens the port and passes each accepted connection
+/// as the new channel, which is tunneled to the local host.
+pub struct RemoteForward {
+
+    /// Identifier of the forwarding inside of the connection
+    id: usize,
+
+    /// Listener of the remote port
+    listener: Listener,
+
+    /// Listened remote port
+    remote_port: u16,
+
+    /// Local host to which connections are forwarded
+    host: String,
+
+    /// Local port to which connections are forwarded
+    port: u16,
+
+    /// Tunnels of the accepted connections
+    tunnels: Vec<Tunnel>
+}
+
+impl RemoteForward {
+
+    /// Requests the server to listen the port on its loopback interface. If port is 0, the server
+    /// chooses any free port. Returns the forwarding and the bound remote port.
+    pub fn listen(session: &Session, id: usize, remote_port: u16, host: String, port: u16) -> Result<(RemoteForward, u16), String> {
+        let listener = session.channel_forward_listen(remote_port, Some("localhost"), None);
+        if listener.is_err() {
+            return Err(format!("Unable to listen remote port {}: {}", remote_port, listener.err().unwrap()));
+        }
+        let (listener, bound_port) = listener.unwrap();
+
+        let forward = RemoteForward {
+            id,
+            listener,
+            remote_port: bound_port,
+            host,
+            port,
+            tunnels: Vec::new()
+        };
+
+        Ok((forward, bound_port))
+    }
+}
+
+impl Forward for RemoteForward {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn describe(&self) -> String {
+        format!("R localhost:{} -> {}:{}", self.remote_port, self.host, self.port)
+    }
+
+    /// Channels for which local connection can't be established are closed
+    fn accept(&mut self, session: &Session) -> bool {
+        let mut accepted = false;
+
+        loop {
+            session.set_blocking(false);
+            let channel = self.listener.accept();
+            session.set_blocking(true);
+            let channel = match channel {
+                Ok(c) => c,
+                Err(_) => break
+            };
+            accepted = true;
+
+            let stream = TcpStream::connect((&self.host[..], self.port));
+            if stream.is_err() {
+                continue;
+            }
+            let stream = stream.unwrap();
+            if stream.set_nonblocking(true).is_ok() {
+                self.tunnels.push(Tunnel::new(channel, Box::new(stream)));
+            }
+        }
+
+        accepted
+    }
+
+    fn tunnels(&mut self) -> &mut Vec<Tunnel> {
+        &mut self.tunnels
+    }
+}
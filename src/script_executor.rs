@@ -1,14 +1,21 @@
 //! This module responsible for performs all actions around LUA virtual machine. He is the core
 //! of execution of a separate script.
 
-use crate::runtime::script_runtime::ScriptRuntime;
-use crate::runtime::connection::Connection;
+use crate::runtime::script_runtime::{ScriptRuntime, ConnectOptions, BLOCK_SIZE};
+use crate::runtime::connection::{Connection, SendFileOptions, ExecOptions, InteractivePrompt, InteractiveResponder, SimpleAuthentication, KeyAuthentication, AgentAuthentication, InteractiveAuthentication};
+use crate::runtime::host_key::HostKeyVerification;
+use crate::runtime::ssh_config::SshConfig;
+use crate::runtime::dir_transfer::{TransferStats, DirTransferOptions};
+use crate::runtime::rate_limit::parse_rate;
+use crate::runtime::shell_reader::decode_output;
+use crate::runtime::sftp::{SftpOp, SftpValue, SftpError, FileInfo, DEFAULT_DIR_MODE, DEFAULT_FILE_MODE};
 use crate::state_printer::state_printer::StatePrinter;
 use crate::out_logger::OutLogger;
 use crate::tsafe::TSafe;
 use std::collections::vec_deque::VecDeque;
-use rlua::{Function, Lua, MetaMethod, Result, UserData, UserDataMethods, Variadic, Table, Value};
+use rlua::{Function, Lua, MetaMethod, Result, UserData, UserDataMethods, Variadic, Table, Value, FromLua, Error, AnyUserData, Context};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::any::Any;
 
 pub struct ScriptConfig {
 
@@ -25,7 +32,19 @@ pub struct ScriptConfig {
     pub args: VecDeque<String>,
 
     /// Additional modules path
-    pub libs: Option<String>
+    pub libs: Option<String>,
+
+    /// Host key verification settings
+    pub host_keys: HostKeyVerification,
+
+    /// OpenSSH client config
+    pub ssh_config: SshConfig,
+
+    /// Default limit of the file transfers rate in bytes per second
+    pub limit_rate: Option<u64>,
+
+    /// Default timeout of the commands executed in the shell in seconds
+    pub exec_timeout: Option<u64>
 }
 
 /// Run execution of the specified script text. This function do all actions needed for run script
@@ -38,7 +57,7 @@ pub fn execute<'script>(cfg: ScriptConfig) {
     lua.context(|lua_ctx| {
 
         // Create script application runtime
-        let runtimer = tsafe!(ScriptRuntime::new(cfg.state_printer.clone(), cfg.out_logger.clone()));
+        let runtimer = tsafe!(ScriptRuntime::new(cfg.state_printer.clone(), cfg.out_logger.clone(), cfg.host_keys.clone(), cfg.ssh_config, cfg.limit_rate, cfg.exec_timeout));
 
         // Extract global context of vm
         let globals = lua_ctx.globals();
@@ -64,11 +83,23 @@ pub fn execute<'script>(cfg: ScriptConfig) {
         //-------------------------------------------------------
 
 
+        // Bind global function 'connect'
+        let runtime = runtimer.clone();
+        let connect =
+            lua_ctx.create_function(move |_, (options): (Table)| {
+                let (options, callback) = connect_options(options)?;
+                let responder = callback.map(lua_responder);
+                let connection = ScriptRuntime::connect(&runtime, options, responder);
+
+                Ok(LuaConnection(connection))
+            }).unwrap();
+        globals.set("connect", connect);
+
         // Bind global function 'connect_ssh_simple'
         let mut runtime = runtimer.clone();
         let connect_ssh_simple =
             lua_ctx.create_function(move |_, (addr, user, password, prompt): (String, String, String, Option<String>)| {
-                let mut connection = runtime.lock().unwrap().connect_ssh_simple(addr, user, password, prompt);
+                let connection = ScriptRuntime::connect_ssh_simple(&runtime, addr, user, password, prompt);
 
                 Ok(LuaConnection(connection))
             }).unwrap();
@@ -78,12 +109,34 @@ pub fn execute<'script>(cfg: ScriptConfig) {
         let mut runtime = runtimer.clone();
         let connect_ssh_key =
             lua_ctx.create_function(move |_, (addr, user, private_key, prompt, passphrase, public_key): (String, String, String, Option<String>, Option<String>, Option<String>)| {
-                let mut connection = runtime.lock().unwrap().connect_ssh_key(addr, user, private_key, prompt, passphrase, public_key);
+                let connection = ScriptRuntime::connect_ssh_key(&runtime, addr, user, private_key, prompt, passphrase, public_key);
 
                 Ok(LuaConnection(connection))
             }).unwrap();
         globals.set("connect_ssh_key", connect_ssh_key);
 
+        // Bind global function 'connect_ssh_agent'
+        let mut runtime = runtimer.clone();
+        let connect_ssh_agent =
+            lua_ctx.create_function(move |_, (addr, user, prompt): (String, String, Option<String>)| {
+                let connection = ScriptRuntime::connect_ssh_agent(&runtime, addr, user, prompt);
+
+                Ok(LuaConnection(connection))
+            }).unwrap();
+        globals.set("connect_ssh_agent", connect_ssh_agent);
+
+        // Bind global function 'connect_ssh_interactive'
+        let runtime = runtimer.clone();
+        let connect_ssh_interactive =
+            lua_ctx.create_function(move |_, (addr, user, prompt, callback): (String, String, Option<String>, Option<Function>)| {
+                let responder = callback.map(lua_responder);
+
+                let connection = ScriptRuntime::connect_ssh_interactive(&runtime, addr, user, prompt, responder);
+
+                Ok(LuaConnection(connection))
+            }).unwrap();
+        globals.set("connect_ssh_interactive", connect_ssh_interactive);
+
         // Bind global function 'print'
         let runtime = runtimer.clone();
         let print =
@@ -127,24 +180,305 @@ pub fn execute<'script>(cfg: ScriptConfig) {
     });
 }
 
+/// Names of the options supported by the connect function
+const CONNECT_OPTIONS: &[&str] = &["host", "port", "user", "password", "key", "public_key", "passphrase",
+    "agent", "interactive", "auth", "prompt", "timeout", "term", "via", "proxy_command", "exec_timeout"];
+
+/// Reads connection options from the table passed to the connect function. Returns options and
+/// the keyboard-interactive callback if it was specified. Unknown options and options with wrong
+/// types are reported as lua errors.
+fn connect_options<'lua>(table: Table<'lua>) -> Result<(ConnectOptions, Option<Function<'lua>>)> {
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, _) = pair?;
+        let known = match &key {
+            Value::String(name) => CONNECT_OPTIONS.iter().any(|o| name.as_bytes() == o.as_bytes()),
+            _ => false
+        };
+        if !known {
+            let name = match key {
+                Value::String(name) => String::from_utf8_lossy(name.as_bytes()).into_owned(),
+                _ => String::from("<not a string>")
+            };
+            return Err(connect_error(format!("unknown option '{}', supported options are: {}", name, CONNECT_OPTIONS.join(", "))));
+        }
+    }
+
+    let host: Option<String> = connect_option(&table, "host", "string")?;
+    if host.is_none() {
+        return Err(connect_error(String::from("option 'host' is required")));
+    }
+    let user: Option<String> = connect_option(&table, "user", "string")?;
+
+    let password: Option<String> = connect_option(&table, "password", "string")?;
+    let key: Option<String> = connect_option(&table, "key", "string")?;
+    let public_key: Option<String> = connect_option(&table, "public_key", "string")?;
+    let passphrase: Option<String> = connect_option(&table, "passphrase", "string")?;
+    let agent: Option<bool> = connect_option(&table, "agent", "boolean")?;
+    let interactive: Value = table.get("interactive")?;
+    let (interactive, callback) = match interactive {
+        Value::Nil => (false, None),
+        Value::Boolean(b) => (b, None),
+        Value::Function(f) => (true, Some(f)),
+        _ => return Err(connect_error(String::from("option 'interactive' must be a boolean or a function")))
+    };
+
+    // Methods are tried in the explicitly specified order, or in the default order - key, agent,
+    // password, interactive
+    let auth: Option<Vec<String>> = connect_option(&table, "auth", "list of strings")?;
+    let auth = if auth.is_some() {
+        auth.unwrap()
+    } else {
+        let mut auth = Vec::new();
+        if key.is_some() { auth.push(String::from("key")); }
+        if agent == Some(true) { auth.push(String::from("agent")); }
+        if password.is_some() { auth.push(String::from("password")); }
+        if interactive { auth.push(String::from("interactive")); }
+        auth
+    };
+    let mut methods: Vec<Box<Any + Send>> = Vec::new();
+    for method in auth {
+        match &method[..] {
+            "password" => {
+                if password.is_none() {
+                    return Err(connect_error(String::from("auth method 'password' requires option 'password'")));
+                }
+                methods.push(Box::new(SimpleAuthentication { password: password.clone().unwrap() }));
+            },
+            "key" => {
+                if key.is_none() {
+                    return Err(connect_error(String::from("auth method 'key' requires option 'key'")));
+                }
+                methods.push(Box::new(KeyAuthentication {
+                    private_key: key.clone().unwrap(),
+                    public_key: public_key.clone(),
+                    passphrase: passphrase.clone()
+                }));
+            },
+            "agent" => methods.push(Box::new(AgentAuthentication {})),
+            "interactive" => methods.push(Box::new(InteractiveAuthentication {})),
+            _ => return Err(connect_error(format!("unknown auth method '{}', supported methods are: password, key, agent, interactive", method)))
+        }
+    }
+
+    let via: Option<AnyUserData> = connect_option(&table, "via", "connection")?;
+    let via = if via.is_some() {
+        let via = via.unwrap();
+        let connection = via.borrow::<LuaConnection>()
+            .map(|c| c.0.clone());
+        if connection.is_err() {
+            return Err(connect_error(String::from("option 'via' must be a connection")));
+        }
+        connection.ok()
+    } else {
+        None
+    };
+    let proxy_command: Option<String> = connect_option(&table, "proxy_command", "string")?;
+    if via.is_some() && proxy_command.is_some() {
+        return Err(connect_error(String::from("options 'via' and 'proxy_command' can't be used together")));
+    }
+
+    let options = ConnectOptions {
+        addr: host.unwrap(),
+        port: connect_option(&table, "port", "number")?,
+        user,
+        auth: methods,
+        prompt: connect_option(&table, "prompt", "string")?,
+        timeout: connect_option(&table, "timeout", "number")?,
+        term: connect_option(&table, "term", "string")?,
+        via,
+        proxy_command,
+        exec_timeout: connect_option(&table, "exec_timeout", "number")?
+    };
+
+    Ok((options, callback))
+}
+
+/// Reads single option of the connect function. Ty is the name of the expected type, used in the
+/// error message.
+fn connect_option<'lua, T: FromLua<'lua>>(table: &Table<'lua>, name: &str, ty: &str) -> Result<Option<T>> {
+    table.get::<_, Option<T>>(name)
+        .map_err(|_| connect_error(format!("option '{}' must be a {}", name, ty)))
+}
+
+/// Creates lua error of the connect function
+fn connect_error(text: String) -> Error {
+    Error::RuntimeError(format!("connect: {}", text))
+}
+
+/// Creates keyboard-interactive responder which passes each prompt to the lua callback as
+/// callback(text, echo, instructions) and uses its result as the answer. Error of the callback
+/// aborts the authentication.
+fn lua_responder<'lua>(callback: Function<'lua>) -> InteractiveResponder<'lua> {
+    Box::new(move |request: &InteractivePrompt| {
+        let mut answers = Vec::new();
+        for (text, echo) in request.prompts.iter() {
+            let answer: Result<Option<String>> = callback.call((text.clone(), *echo, request.instructions.clone()));
+            if answer.is_err() {
+                return Err(answer.err().unwrap().to_string());
+            }
+            answers.push(answer.unwrap().unwrap_or(String::new()));
+        }
+
+        Ok(answers)
+    })
+}
+
 /// Lua representation of the runtime Connection object
 struct LuaConnection(TSafe<Connection>);
 
 impl UserData for LuaConnection {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method_mut("exec", |lua_ctx, mut s, (cmd, prompt, with_prompt): (String, Option<String>, Option<bool>)| {
-            let r = s.0.lock().unwrap().exec(cmd, prompt, with_prompt);
+        methods.add_method_mut("exec", |lua_ctx, mut s, (cmd, prompt, with_prompt, options): (String, Option<String>, Option<bool>, Option<Table>)| {
+            let (options, escape, raw) = exec_options(options)?;
+            let r = s.0.lock().unwrap().exec(cmd, prompt, with_prompt, options);
 
             let t = lua_ctx.create_table().unwrap();
 
-            t.set("error", r.0);
-            t.set("out", r.1);
+            match r {
+                Ok(result) => {
+                    let out = if raw {
+                        Value::String(lua_ctx.create_string(&result.out)?)
+                    } else {
+                        Value::String(lua_ctx.create_string(&decode_output(&result.out, escape))?)
+                    };
+
+                    // Output captured before the error is returned in the separate field
+                    if result.error.is_some() {
+                        t.set("error", true);
+                        t.set("out", result.error.unwrap());
+                        t.set("output", out);
+                        t.set("timeout", result.timed_out);
+                    } else {
+                        t.set("error", false);
+                        t.set("out", out);
+                        t.set("exit_code", result.exit_code);
+                    }
+                },
+                Err(err) => {
+                    t.set("error", true);
+                    t.set("out", err);
+                }
+            }
+
+            Ok(t)
+        });
+
+        methods.add_method_mut("run", |lua_ctx, mut s, (cmd): (String)| {
+            let r = s.0.lock().unwrap().run(cmd);
+
+            let t = lua_ctx.create_table().unwrap();
+
+            match r {
+                Ok(result) => {
+                    t.set("error", false);
+                    t.set("out", result.stdout.clone());
+                    t.set("exit_code", result.exit_code);
+                    t.set("stdout", result.stdout);
+                    t.set("stderr", result.stderr);
+                    t.set("signal", result.signal);
+                },
+                Err(err) => {
+                    t.set("error", true);
+                    t.set("out", err);
+                }
+            }
 
             Ok(t)
         });
 
-        methods.add_method_mut("send_file", |lua_ctx, mut s, (source, dest): (String, String)| {
-            let r = s.0.lock().unwrap().send_file(source, dest);
+        methods.add_method_mut("send_file", |lua_ctx, mut s, (source, dest, options): (String, String, Option<Table>)| {
+            let options = send_file_options(options)?;
+            let r = s.0.lock().unwrap().send_file(source, dest, options);
+
+            let t = lua_ctx.create_table().unwrap();
+            match r {
+                Ok(report) => {
+                    t.set("error", false);
+                    t.set("out", "");
+                    t.set("mode", format!("{:o}", report.mode));
+                    if let Some((mtime, atime)) = report.times {
+                        t.set("mtime", mtime);
+                        t.set("atime", atime);
+                    }
+                    t.set("owner", report.owner);
+                    t.set("group", report.group);
+                    t.set("skipped", report.skipped);
+                    t.set("transferred", report.transferred);
+                    t.set("resumed", report.resumed);
+                    t.set("sha256", report.sha256);
+                },
+                Err(err) => {
+                    t.set("error", true);
+                    t.set("out", err);
+                }
+            }
+
+            Ok(t)
+        });
+
+        methods.add_method_mut("forward_local", |lua_ctx, mut s, (local_port, host, port): (u16, String, u16)| {
+            let r = s.0.lock().unwrap().forward_local(local_port, host, port);
+
+            let forward = match r {
+                Ok((id, bound_port)) => LuaForward {
+                    connection: s.0.clone(),
+                    id: Some(id),
+                    port: bound_port,
+                    error: None
+                },
+                Err(err) => LuaForward {
+                    connection: s.0.clone(),
+                    id: None,
+                    port: local_port,
+                    error: Some(err)
+                }
+            };
+
+            Ok(forward)
+        });
+
+        methods.add_method_mut("forward_remote", |lua_ctx, mut s, (remote_port, host, port): (u16, String, u16)| {
+            let r = s.0.lock().unwrap().forward_remote(remote_port, host, port);
+
+            let forward = match r {
+                Ok((id, bound_port)) => LuaForward {
+                    connection: s.0.clone(),
+                    id: Some(id),
+                    port: bound_port,
+                    error: None
+                },
+                Err(err) => LuaForward {
+                    connection: s.0.clone(),
+                    id: None,
+                    port: remote_port,
+                    error: Some(err)
+                }
+            };
+
+            Ok(forward)
+        });
+
+        methods.add_method_mut("forwards", |lua_ctx, mut s, (): ()| {
+            let list = s.0.lock().unwrap().forwards();
+
+            Ok(list.into_iter().map(|(_, description)| description).collect::<Vec<String>>())
+        });
+
+        methods.add_method_mut("sftp", |lua_ctx, mut s, (): ()| {
+            let r = s.0.lock().unwrap().sftp(SftpOp::Init);
+
+            Ok(LuaSftp {
+                connection: s.0.clone(),
+                error: r.err().map(|e| e.text)
+            })
+        });
+
+        methods.add_method_mut("fetch_file", |lua_ctx, mut s, (source, dest, options): (String, String, Option<Table>)| {
+            let rate = match options {
+                Some(options) => transfer_rate(options.get("rate")?)?,
+                None => None
+            };
+            let r = s.0.lock().unwrap().fetch_file(source, dest, rate);
 
             let t = lua_ctx.create_table().unwrap();
             t.set("error", r.0);
@@ -153,6 +487,23 @@ impl UserData for LuaConnection {
             Ok(t)
         });
 
+        methods.add_method_mut("send_dir", |lua_ctx, mut s, (source, dest, options): (String, String, Option<Table>)| {
+            let options = dir_transfer_options(options)?;
+            let r = s.0.lock().unwrap().send_dir(source, dest, options);
+
+            transfer_result(lua_ctx, r)
+        });
+
+        methods.add_method_mut("fetch_dir", |lua_ctx, mut s, (source, dest, options): (String, String, Option<Table>)| {
+            let options = dir_transfer_options(options)?;
+            if options.block_delta {
+                return Err(Error::RuntimeError(String::from("option 'block_delta' is not supported by fetch_dir")));
+            }
+            let r = s.0.lock().unwrap().fetch_dir(source, dest, options);
+
+            transfer_result(lua_ctx, r)
+        });
+
         methods.add_method_mut("set_prompt", |lua_ctx, mut s, (pattern): (String)| {
             let r = s.0.lock().unwrap().set_prompt(pattern);
 
@@ -175,4 +526,385 @@ impl UserData for LuaConnection {
             }
         });
     }
-}
\ No newline at end of file
+}
+
+/// Parses options of the exec. Returns options of the execution and flags of escaping of the
+/// invalid UTF-8 sequences and of the raw output.
+fn exec_options(options: Option<Table>) -> Result<(ExecOptions, bool, bool)> {
+    if options.is_none() {
+        return Ok((ExecOptions::default(), false, false));
+    }
+    let options = options.unwrap();
+
+    let timeout: Option<u64> = options.get("timeout")
+        .map_err(|_| Error::RuntimeError(String::from("option 'timeout' must be a non-negative number")))?;
+    let interrupt: Option<bool> = options.get("interrupt")
+        .map_err(|_| Error::RuntimeError(String::from("option 'interrupt' must be a boolean")))?;
+
+    let invalid: Option<String> = options.get("invalid")
+        .map_err(|_| Error::RuntimeError(String::from("option 'invalid' must be a string")))?;
+    let escape = match invalid.as_ref().map(|s| &s[..]) {
+        None | Some("replace") => false,
+        Some("escape") => true,
+        Some(other) => return Err(Error::RuntimeError(format!("unknown value of option 'invalid' - '{}', supported values are: replace, escape", other)))
+    };
+    let raw: Option<bool> = options.get("raw")
+        .map_err(|_| Error::RuntimeError(String::from("option 'raw' must be a boolean")))?;
+
+    let exec_options = ExecOptions {
+        timeout,
+        interrupt: interrupt.unwrap_or(false)
+    };
+
+    Ok((exec_options, escape, raw.unwrap_or(false)))
+}
+
+/// Parses options of the file sending
+fn send_file_options(options: Option<Table>) -> Result<SendFileOptions> {
+    if options.is_none() {
+        return Ok(SendFileOptions::default());
+    }
+    let options = options.unwrap();
+
+    let mode: Value = options.get("mode")?;
+    let mode = match mode {
+        Value::Nil => None,
+        mode => Some(file_mode(Some(mode), DEFAULT_FILE_MODE)?)
+    };
+    let preserve: Option<bool> = options.get("preserve")
+        .map_err(|_| Error::RuntimeError(String::from("option 'preserve' must be a boolean")))?;
+    let owner: Option<String> = options.get("owner")
+        .map_err(|_| Error::RuntimeError(String::from("option 'owner' must be a string or a number")))?;
+    let group: Option<String> = options.get("group")
+        .map_err(|_| Error::RuntimeError(String::from("option 'group' must be a string or a number")))?;
+
+    let skip_unchanged: Option<bool> = options.get("skip_unchanged")
+        .map_err(|_| Error::RuntimeError(String::from("option 'skip_unchanged' must be a boolean")))?;
+    let block_delta: Option<bool> = options.get("block_delta")
+        .map_err(|_| Error::RuntimeError(String::from("option 'block_delta' must be a boolean")))?;
+    let resume: Option<bool> = options.get("resume")
+        .map_err(|_| Error::RuntimeError(String::from("option 'resume' must be a boolean")))?;
+    let verify: Option<bool> = options.get("verify")
+        .map_err(|_| Error::RuntimeError(String::from("option 'verify' must be a boolean")))?;
+    let rate = transfer_rate(options.get("rate")?)?;
+
+    Ok(SendFileOptions {
+        mode,
+        preserve: preserve.unwrap_or(false),
+        owner,
+        group,
+        skip_unchanged: skip_unchanged.unwrap_or(false),
+        block_delta: block_delta.unwrap_or(false),
+        resume: resume.unwrap_or(false),
+        verify: verify.unwrap_or(false),
+        rate
+    })
+}
+
+/// Parses options of the directory transfer
+fn dir_transfer_options(options: Option<Table>) -> Result<DirTransferOptions> {
+    if options.is_none() {
+        return Ok(DirTransferOptions::default());
+    }
+    let options = options.unwrap();
+
+    let exclude: Option<Vec<String>> = options.get("exclude")
+        .map_err(|_| Error::RuntimeError(String::from("option 'exclude' must be a list of strings")))?;
+    let skip_unchanged: Option<bool> = options.get("skip_unchanged")
+        .map_err(|_| Error::RuntimeError(String::from("option 'skip_unchanged' must be a boolean")))?;
+    let block_delta: Option<bool> = options.get("block_delta")
+        .map_err(|_| Error::RuntimeError(String::from("option 'block_delta' must be a boolean")))?;
+    let rate = transfer_rate(options.get("rate")?)?;
+
+    Ok(DirTransferOptions {
+        exclude: exclude.unwrap_or(Vec::new()),
+        skip_unchanged: skip_unchanged.unwrap_or(false),
+        block_delta: block_delta.unwrap_or(false),
+        rate
+    })
+}
+
+/// Converts result of the directory transfer to the lua table with fields error, out, files,
+/// dirs, symlinks and bytes
+fn transfer_result<'lua>(lua_ctx: Context<'lua>, result: std::result::Result<TransferStats, String>) -> Result<Table<'lua>> {
+    let t = lua_ctx.create_table()?;
+
+    match result {
+        Ok(stats) => {
+            t.set("error", false)?;
+            t.set("out", "")?;
+            t.set("files", stats.files)?;
+            t.set("dirs", stats.dirs)?;
+            t.set("symlinks", stats.symlinks)?;
+            t.set("skipped", stats.skipped)?;
+            t.set("bytes", stats.bytes)?;
+        },
+        Err(err) => {
+            t.set("error", true)?;
+            t.set("out", err)?;
+        }
+    }
+
+    Ok(t)
+}
+
+/// Lua representation of the port forwarding of the connection. If the forwarding was failed, it
+/// contains text of the error.
+struct LuaForward {
+    connection: TSafe<Connection>,
+    id: Option<usize>,
+    port: u16,
+    error: Option<String>
+}
+
+impl UserData for LuaForward {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("port", |lua_ctx, s, (): ()| {
+            Ok(s.port)
+        });
+
+        methods.add_method_mut("close", |lua_ctx, mut s, (): ()| {
+            if s.id.is_some() {
+                s.connection.lock().unwrap().close_forward(s.id.take().unwrap());
+            }
+
+            Ok(())
+        });
+
+        methods.add_method("is_error", |lua_ctx, s, (): ()| {
+            Ok(s.error.is_some())
+        });
+
+        methods.add_method("get_error", |lua_ctx, s, (): ()| {
+            Ok(s.error.clone())
+        });
+    }
+}
+
+/// Lua representation of the sftp subsystem of the connection. If the subsystem can't be opened,
+/// it contains text of the error.
+struct LuaSftp {
+    connection: TSafe<Connection>,
+    error: Option<String>
+}
+
+impl LuaSftp {
+
+    /// Executes the sftp operation and converts its result to the lua table
+    fn execute<'lua>(&self, lua_ctx: Context<'lua>, op: SftpOp) -> Result<Table<'lua>> {
+        let r = self.connection.lock().unwrap().sftp(op);
+
+        sftp_result(lua_ctx, r)
+    }
+}
+
+impl UserData for LuaSftp {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("list", |lua_ctx, s, (path): (String)| {
+            s.execute(lua_ctx, SftpOp::List(path))
+        });
+
+        methods.add_method("stat", |lua_ctx, s, (path): (String)| {
+            s.execute(lua_ctx, SftpOp::Stat(path))
+        });
+
+        methods.add_method("lstat", |lua_ctx, s, (path): (String)| {
+            s.execute(lua_ctx, SftpOp::Lstat(path))
+        });
+
+        methods.add_method("mkdir", |lua_ctx, s, (path, mode): (String, Option<Value>)| {
+            let mode = file_mode(mode, DEFAULT_DIR_MODE)?;
+            s.execute(lua_ctx, SftpOp::Mkdir(path, mode))
+        });
+
+        methods.add_method("rmdir", |lua_ctx, s, (path): (String)| {
+            s.execute(lua_ctx, SftpOp::Rmdir(path))
+        });
+
+        methods.add_method("remove", |lua_ctx, s, (path): (String)| {
+            s.execute(lua_ctx, SftpOp::Remove(path))
+        });
+
+        methods.add_method("rename", |lua_ctx, s, (source, dest): (String, String)| {
+            s.execute(lua_ctx, SftpOp::Rename(source, dest))
+        });
+
+        methods.add_method("symlink", |lua_ctx, s, (target, path): (String, String)| {
+            s.execute(lua_ctx, SftpOp::Symlink(target, path))
+        });
+
+        methods.add_method("readlink", |lua_ctx, s, (path): (String)| {
+            s.execute(lua_ctx, SftpOp::Readlink(path))
+        });
+
+        methods.add_method("chmod", |lua_ctx, s, (path, mode): (String, Value)| {
+            let mode = file_mode(Some(mode), DEFAULT_FILE_MODE)?;
+            s.execute(lua_ctx, SftpOp::Chmod(path, mode))
+        });
+
+        methods.add_method("read", |lua_ctx, s, (path): (String)| {
+            s.execute(lua_ctx, SftpOp::Read(path))
+        });
+
+        methods.add_method("write", |lua_ctx, s, (path, data, mode): (String, rlua::String, Option<Value>)| {
+            let mode = file_mode(mode, DEFAULT_FILE_MODE)?;
+            s.execute(lua_ctx, SftpOp::Write(path, data.as_bytes().to_vec(), mode))
+        });
+
+        methods.add_method("open", |lua_ctx, s, (path, mode): (String, Option<String>)| {
+            let r = s.connection.lock().unwrap().sftp(SftpOp::Open(path, mode.unwrap_or(String::from("r"))));
+
+            let t = lua_ctx.create_table()?;
+            match r {
+                Ok(SftpValue::Handle(id)) => {
+                    t.set("error", false)?;
+                    t.set("file", LuaSftpFile {
+                        connection: s.connection.clone(),
+                        id: Some(id)
+                    })?;
+                },
+                Ok(_) => panic!(),
+                Err(err) => {
+                    t.set("error", true)?;
+                    t.set("out", err.text)?;
+                    t.set("code", err.code)?;
+                }
+            }
+
+            Ok(t)
+        });
+
+        methods.add_method("is_error", |lua_ctx, s, (): ()| {
+            Ok(s.error.is_some())
+        });
+
+        methods.add_method("get_error", |lua_ctx, s, (): ()| {
+            Ok(s.error.clone())
+        });
+    }
+}
+
+/// Lua representation of the file opened through the sftp subsystem
+struct LuaSftpFile {
+    connection: TSafe<Connection>,
+    id: Option<usize>
+}
+
+impl LuaSftpFile {
+
+    /// Executes operation with the file and converts its result to the lua table. If the file was
+    /// closed, the error is returned.
+    fn execute<'lua>(&self, lua_ctx: Context<'lua>, op: &Fn(usize) -> SftpOp) -> Result<Table<'lua>> {
+        let r = if self.id.is_some() {
+            self.connection.lock().unwrap().sftp(op(self.id.unwrap()))
+        } else {
+            Err(SftpError { code: None, text: String::from("File is closed") })
+        };
+
+        sftp_result(lua_ctx, r)
+    }
+}
+
+impl UserData for LuaSftpFile {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("read", |lua_ctx, s, (count): (Option<usize>)| {
+            let count = count.unwrap_or(BLOCK_SIZE);
+            s.execute(lua_ctx, &|id| SftpOp::FileRead(id, count))
+        });
+
+        methods.add_method("write", |lua_ctx, s, (data): (rlua::String)| {
+            let data = data.as_bytes().to_vec();
+            s.execute(lua_ctx, &|id| SftpOp::FileWrite(id, data.clone()))
+        });
+
+        methods.add_method("seek", |lua_ctx, s, (pos): (u64)| {
+            s.execute(lua_ctx, &|id| SftpOp::FileSeek(id, pos))
+        });
+
+        methods.add_method_mut("close", |lua_ctx, mut s, (): ()| {
+            if s.id.is_some() {
+                s.connection.lock().unwrap().sftp(SftpOp::FileClose(s.id.take().unwrap()));
+            }
+
+            Ok(())
+        });
+    }
+}
+
+/// Converts result of the sftp operation to the lua table. Table always contains the error field,
+/// which indicates that the operation was failed. In this case the out field contains text of the
+/// error and the code field contains SFTP status code (if the error was reported by the server).
+/// Otherwise, fields of the table depend on the operation.
+fn sftp_result<'lua>(lua_ctx: Context<'lua>, result: std::result::Result<SftpValue, SftpError>) -> Result<Table<'lua>> {
+    let t = lua_ctx.create_table()?;
+
+    match result {
+        Ok(value) => {
+            t.set("error", false)?;
+            match value {
+                SftpValue::None | SftpValue::Handle(_) => {},
+                SftpValue::Stat(info) => fill_file_info(&t, info)?,
+                SftpValue::List(list) => {
+                    let entries = lua_ctx.create_table()?;
+                    for (i, info) in list.into_iter().enumerate() {
+                        let entry = lua_ctx.create_table()?;
+                        fill_file_info(&entry, info)?;
+                        entries.set(i + 1, entry)?;
+                    }
+                    t.set("entries", entries)?;
+                },
+                SftpValue::Path(path) => t.set("path", path)?,
+                SftpValue::Data(data) => t.set("data", lua_ctx.create_string(&data)?)?
+            }
+        },
+        Err(err) => {
+            t.set("error", true)?;
+            t.set("out", err.text)?;
+            t.set("code", err.code)?;
+        }
+    }
+
+    Ok(t)
+}
+
+/// Sets attributes of the remote file to the lua table
+fn fill_file_info(t: &Table, info: FileInfo) -> Result<()> {
+    t.set("name", info.name)?;
+    t.set("type", info.kind)?;
+    t.set("size", info.size)?;
+    t.set("mode", info.mode)?;
+    t.set("uid", info.uid)?;
+    t.set("gid", info.gid)?;
+    t.set("atime", info.atime)?;
+    t.set("mtime", info.mtime)?;
+
+    Ok(())
+}
+
+/// Converts file mode passed by the script to the number. Mode may be a number or a string with
+/// octal number ('755').
+fn file_mode(mode: Option<Value>, default: i32) -> Result<i32> {
+    match mode {
+        None | Some(Value::Nil) => Ok(default),
+        Some(Value::Integer(n)) => Ok(n as i32),
+        Some(Value::Number(n)) => Ok(n as i32),
+        Some(Value::String(s)) => {
+            let text = s.to_str()?;
+            i32::from_str_radix(text, 8)
+                .map_err(|_| Error::RuntimeError(format!("bad file mode '{}'", text)))
+        },
+        _ => Err(Error::RuntimeError(String::from("file mode must be a number or an octal string")))
+    }
+}
+
+/// Parses the rate option of the file transfer, number of bytes per second or string with optional
+/// suffix K, M or G ('512K')
+fn transfer_rate(rate: Option<Value>) -> Result<Option<u64>> {
+    match rate {
+        None | Some(Value::Nil) => Ok(None),
+        Some(Value::Integer(n)) if n >= 0 => Ok(Some(n as u64)),
+        Some(Value::Number(n)) if n >= 0.0 => Ok(Some(n as u64)),
+        Some(Value::String(s)) => parse_rate(s.to_str()?).map(Some).map_err(Error::RuntimeError),
+        _ => Err(Error::RuntimeError(String::from("option 'rate' must be a non-negative number or a string")))
+    }
+}
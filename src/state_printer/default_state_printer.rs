@@ -69,6 +69,21 @@ impl StatePrinter for DefaultStatePrinter {
         });
     }
 
+    /// Sets transferred and total bytes of the current component if it is ProgressComponent, the
+    /// transfer statistics are shown after the next update of the bar title. If the current
+    /// component is not ProgressState, panic will be caused.
+    fn set_transferred(&mut self, done: u64, total: Option<u64>) {
+        let current= self.current.as_ref().unwrap().clone();
+        let mut current = current.lock().unwrap();
+
+        match_downcast_mut!(current, {
+            s: ProgressState => {
+                s.set_transferred(done, total);
+            },
+            _ => panic!("Current state is not 'progress'")
+        });
+    }
+
     /// Completes the current component. What will do this action, depends on type of the current
     /// component
     fn complete_current(&self) {
@@ -98,6 +113,21 @@ impl StatePrinter for DefaultStatePrinter {
         });
     }
 
+    /// Prints warning to the current component, the component is not completed
+    fn warn_current(&self, text: &str) {
+        let current= self.current.as_ref().unwrap().clone();
+        let mut current = current.lock().unwrap();
+        match_downcast_ref!(current, {
+            s: OneLineState => {
+                s.warning(text)
+            },
+            s: ProgressState => {
+                s.warning(text)
+            },
+            _ => panic!("Unsupported print state")
+        });
+    }
+
     /// Prints text  to the current state
     fn print_to_current(&self, text: &str) {
         let current= self.current.as_ref().unwrap().clone();
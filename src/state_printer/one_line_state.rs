@@ -51,6 +51,12 @@ impl OneLineState {
         stdout().flush();
     }
 
+    /// Prints warning with specified text, the state is not completed
+    pub fn warning(&self, text: &str) {
+        println!("  | {}WARNING: {}{}", color::Fg(color::Yellow), text, style::Reset);
+        stdout().flush();
+    }
+
     /// Prints message from the script
     pub fn print(&self, text: &str) {
         let text: Vec<char> = text.chars().collect();
@@ -1,12 +1,17 @@
 //! State with title, body and progress bar. Example of this state type is the SEND FILE operation
 
 use crate::tsafe::TSafe;
+use crate::runtime::rate_limit::format_rate;
 use super::state_printer::LINE_SIZE;
 use termion::{color, style};
 use std::any::Any;
 use std::sync::{Mutex, Arc};
 use std::io::Write;
 use std::io::stdout;
+use std::time::{Duration, Instant};
+
+/// Minimal interval between samples of the current throughput
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(1000);
 
 pub struct ProgressState {
 
@@ -22,11 +27,26 @@ pub struct ProgressState {
     /// Empty space after main block of text in the first line
     filler: String,
 
-    /// Text behind progress bar
+    /// Text behind progress bar, including transfer statistics
     bar_title: String,
 
     /// Current progress in percentage
-    progress_size: f32
+    progress_size: f32,
+
+    /// Time of the state creation
+    started: Instant,
+
+    /// Transferred bytes at the first set_transferred (offset of the resumed transfer)
+    start_bytes: Option<u64>,
+
+    /// Transferred and total (if known) bytes from the last set_transferred
+    bytes: Option<(u64, Option<u64>)>,
+
+    /// Time and transferred bytes of the last throughput sample
+    sample: (Instant, u64),
+
+    /// Throughput between the last two samples in bytes per second
+    current_rate: Option<u64>
 }
 
 impl ProgressState {
@@ -42,13 +62,20 @@ impl ProgressState {
         let filler_len = (line_len - (title_len + body_len + ad_len)) as i32;
         let filler = (0..filler_len).map(|_| " ").collect::<String>();
 
+        let now = Instant::now();
+
         ProgressState {
             title: String::from(title),
             body: String::from(body),
             offset: line_len,
             filler,
             bar_title: String::from(bar_title),
-            progress_size: 0.0
+            progress_size: 0.0,
+            started: now,
+            start_bytes: None,
+            bytes: None,
+            sample: (now, 0),
+            current_rate: None
         }
     }
 
@@ -77,8 +104,21 @@ impl ProgressState {
         }
     }
 
-    /// Update text behind the progress bar
+    /// Set transferred and total bytes. The first call sets the starting point of the transfer
+    /// statistics, they are shown by the next update of the bar title.
+    pub fn set_transferred(&mut self, done: u64, total: Option<u64>) {
+        if self.start_bytes.is_none() {
+            self.start_bytes = Some(done);
+            self.sample = (Instant::now(), done);
+        }
+        self.bytes = Some((done, total));
+    }
+
+    /// Update text behind the progress bar. If the transferred bytes are set, the transfer
+    /// statistics are shown after it.
     pub fn update_bar_title(&mut self, bar_title: &str) {
+        let bar_title = self.with_statistics(bar_title);
+
         let old_len = self.bar_title.len() as u16;
         print!("{}", termion::cursor::Left(old_len));
         for i in 0..old_len {
@@ -87,10 +127,50 @@ impl ProgressState {
         print!("{}", termion::cursor::Left(old_len));
         print!("{}", bar_title);
 
-        self.bar_title = String::from(bar_title);
+        self.bar_title = bar_title;
         stdout().flush();
     }
 
+    /// Internal API - updates the transfer statistics and appends them to the bar title. Current
+    /// throughput is measured between samples taken at least a second apart, average speed and
+    /// ETA are measured from the state creation. ETA is not shown if the total is unknown.
+    fn with_statistics(&mut self, bar_title: &str) -> String {
+        if self.bytes.is_none() {
+            return String::from(bar_title);
+        }
+        let (done, total) = self.bytes.unwrap();
+
+        let now = Instant::now();
+        let since_sample = now.duration_since(self.sample.0);
+        if since_sample >= SAMPLE_INTERVAL && done >= self.sample.1 {
+            self.current_rate = Some(((done - self.sample.1) as f64 / since_sample.as_secs_f64()) as u64);
+            self.sample = (now, done);
+        }
+
+        let elapsed = now.duration_since(self.started);
+        let average = self.average_rate(done, elapsed);
+        let mut text = format!("{}  {}", bar_title, format_rate(self.current_rate.unwrap_or(average)));
+        text.push_str(&format!(" avg {} {}", format_rate(average), format_duration(elapsed)));
+        if let Some(total) = total {
+            if done < total && average > 0 {
+                text.push_str(&format!(" ETA {}", format_duration(Duration::from_secs((total - done) / average))));
+            }
+        }
+
+        text
+    }
+
+    /// Internal API - returns average rate of the transfer in bytes per second
+    fn average_rate(&self, done: u64, elapsed: Duration) -> u64 {
+        let secs = elapsed.as_secs_f64();
+        let transferred = done.saturating_sub(self.start_bytes.unwrap_or(0));
+        if secs > 0.0 {
+            (transferred as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+
     /// Completes state with error with specified text
     pub fn error(&self, text: &str) {
         println!("  | {}ERROR: {}{}", color::Fg(color::Red), text, style::Reset);
@@ -98,9 +178,24 @@ impl ProgressState {
         stdout().flush();
     }
 
-    /// Completes state in normal mode (prints new line character)
+    /// Prints warning with specified text, the state is not completed
+    pub fn warning(&self, text: &str) {
+        println!("  | {}WARNING: {}{}", color::Fg(color::Yellow), text, style::Reset);
+        stdout().flush();
+    }
+
+    /// Completes state in normal mode (prints new line character). Summary of the transfer is
+    /// printed if some bytes were transferred.
     pub fn complete(&self) {
         print!("\n");
+        if self.bytes.is_some() && self.start_bytes.is_some() {
+            let (done, _) = self.bytes.unwrap();
+            let elapsed = self.started.elapsed();
+            let transferred = done.saturating_sub(self.start_bytes.unwrap());
+            if transferred > 0 {
+                println!("  | {} bytes in {}, average {}", transferred, format_duration(elapsed), format_rate(self.average_rate(done, elapsed)));
+            }
+        }
         stdout().flush();
     }
 
@@ -138,4 +233,75 @@ impl ProgressState {
         print!("{}  | [                                                  ] {}", style::Bold, &self.bar_title);
         stdout().flush();
     }
-}
\ No newline at end of file
+}
+
+/// Formats duration as mm:ss or h:mm:ss
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the state which was created the specified seconds ago
+    fn started_ago(secs: u64) -> ProgressState {
+        let mut state = ProgressState::new("SEND FILE", "a -> b", "");
+        state.started = Instant::now() - Duration::from_secs(secs);
+        state
+    }
+
+    #[test]
+    fn format_duration_sub_second() {
+        assert_eq!(format_duration(Duration::from_millis(0)), "00:00");
+        assert_eq!(format_duration(Duration::from_millis(999)), "00:00");
+    }
+
+    #[test]
+    fn format_duration_minutes() {
+        assert_eq!(format_duration(Duration::from_secs(61)), "01:01");
+        assert_eq!(format_duration(Duration::from_secs(3599)), "59:59");
+    }
+
+    #[test]
+    fn format_duration_hour_plus() {
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1:00:00");
+        assert_eq!(format_duration(Duration::from_secs(100000)), "27:46:40");
+    }
+
+    #[test]
+    fn statistics_without_bytes() {
+        let mut state = started_ago(10);
+        assert_eq!(state.with_statistics("files 1/2"), "files 1/2");
+    }
+
+    #[test]
+    fn statistics_with_total() {
+        let mut state = started_ago(10);
+        state.set_transferred(0, Some(100));
+        state.set_transferred(55, Some(100));
+        assert_eq!(state.with_statistics("100/55"), "100/55  5 B/s avg 5 B/s 00:10 ETA 00:09");
+    }
+
+    #[test]
+    fn statistics_of_resumed_transfer() {
+        // Bytes before the first set_transferred are not counted in the average
+        let mut state = started_ago(10);
+        state.set_transferred(1000, Some(1100));
+        state.set_transferred(1055, Some(1100));
+        assert_eq!(state.with_statistics("1100/1055"), "1100/1055  5 B/s avg 5 B/s 00:10 ETA 00:09");
+    }
+
+    #[test]
+    fn statistics_with_unknown_total() {
+        let mut state = started_ago(10);
+        state.set_transferred(0, None);
+        state.set_transferred(55, None);
+        assert_eq!(state.with_statistics("55"), "55  5 B/s avg 5 B/s 00:10");
+    }
+}
@@ -21,6 +21,7 @@ impl StatePrinter for SilentStatePrinter {
     fn add_progress(&mut self, title: &str, body: &str, bar_title: &str) {}
     fn set_progress(&mut self, count: f32) {}
     fn update_bar_title(&mut self, bar_title: &str) {}
+    fn set_transferred(&mut self, done: u64, total: Option<u64>) {}
     fn complete_current(&self) {}
 
     fn error_current(&self, text: &str) {
@@ -28,6 +29,11 @@ impl StatePrinter for SilentStatePrinter {
         stdout().flush();
     }
 
+    fn warn_current(&self, text: &str) {
+        println!("WARNING: {}", text);
+        stdout().flush();
+    }
+
     fn print_to_current(&self, text: &str) {
         println!("{}", text);
     }
@@ -14,8 +14,10 @@ pub trait StatePrinter {
     fn add_progress(&mut self, title: &str, body: &str, bar_title: &str);
     fn set_progress(&mut self, count: f32);
     fn update_bar_title(&mut self, bar_title: &str);
+    fn set_transferred(&mut self, done: u64, total: Option<u64>);
     fn complete_current(&self);
     fn error_current(&self, text: &str);
+    fn warn_current(&self, text: &str);
     fn print_to_current(&self, text: &str);
     fn print_read_request(&self, prompt: &str);
 }